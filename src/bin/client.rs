@@ -14,7 +14,7 @@ use bevy::{
     },
     DefaultPlugins,
 };
-use craft2::{VoxelVolumePlugin, VoxelVolumeRenderPlugin, VoxelVolume, VoxelBundle, color_to_rgba_u32, u24_to_bytes, utility::{PlayerPlugin, MovementSettings, FlyCam}};
+use craft2::{VoxelVolumePlugin, VoxelVolumeRenderPlugin, VoxelVolume, VoxelBundle, Octree, color_to_rgba_u32, u24_to_bytes, utility::{PlayerPlugin, MovementSettings, FlyCam}};
 use rand::{Rng, distributions::Uniform, distributions::Distribution };
 
 
@@ -63,14 +63,10 @@ fn setup(
     //     test2.palette[x] = color_to_rgba_u32(Color::rgba(r, g, b, 1.0));
     // }
 
-    for x in 0..=255 {
-        for y in 0..=8 {
-            for z in 0..=255 {
-                let n1: u32 = uni2.sample(&mut rng);
-                test.data.add_data(x, y, z, u24_to_bytes(n1));
-            }
-        }
-    }
+    test.data = Octree::from_fn([256, 9, 256], |_, _, _| {
+        let n1: u32 = uni2.sample(&mut rng);
+        Some(u24_to_bytes(n1))
+    });
 
     // for x in 0..=15 {
     //     for y in 0..=15 {
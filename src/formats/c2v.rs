@@ -0,0 +1,406 @@
+use std::{collections::BTreeMap, fmt, io::{self, Write}};
+
+use bevy::{asset::{AssetLoader, LoadContext, LoadedAsset}, math::Vec3, utils::BoxedFuture};
+
+use crate::{VoxelVolume, Octree, IndirectionGrid, u24_to_bytes};
+
+use super::{lz, reader::{ByteReader, ReadError}};
+
+/// The first bytes of every `.c2v` file.
+pub const C2V_MAGIC: [u8; 4] = *b"C2V\0";
+
+/// The format version written by [`VoxelVolume::write_c2v`].
+pub const C2V_VERSION: u16 = 2;
+
+/// Set in the header flags when the file ends with a metadata section.
+const FLAG_METADATA: u16 = 1;
+
+/// The size of a grid in the octree pool of a version 1 file.
+const V1_GRID_LEN: usize = 9 * 4;
+
+/// Loads our own `.c2v` volume files. The volume is the default asset; read the file with
+/// [`C2vFile::parse`] to get at its metadata.
+#[derive(Default)]
+pub struct C2vLoader;
+
+impl AssetLoader for C2vLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let c2v = C2vFile::parse(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(c2v.volume));
+
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["c2v"]
+    }
+}
+
+/// A volume stored in our native `.c2v` format. Unlike the formats of other editors, it keeps
+/// the octree exactly as it was, including collapsed cells, LOD cells, free grids and attachments,
+/// and loading it doesn't rebuild the octree voxel by voxel.
+///
+/// All values are little-endian. A file starts with a header:
+///
+/// | Field        | Type         |                                               |
+/// |--------------|--------------|-----------------------------------------------|
+/// | `magic`      | `[u8; 4]`    | [`C2V_MAGIC`]                                 |
+/// | `version`    | `u16`        | [`C2V_VERSION`] when written                  |
+/// | `flags`      | `u16`        | bit 0: a metadata section follows the octree  |
+/// | `resolution` | `f32`        | [`VoxelVolume::resolution`]                   |
+/// | `size`       | `[f32; 3]`   | [`VoxelVolume::size`]                         |
+/// | `palette`    | `[u32; 256]` | [`VoxelVolume::palette`]                      |
+///
+/// followed by the octree: its encoded length as a `u32`, its compressed length as a `u32`, and
+/// the output of [`Octree::write_compact`] compressed with an LZ4-style block encoding. The
+/// metadata section is a `u32` entry count followed by each key and value, as a `u32` length and
+/// UTF-8 bytes.
+///
+/// The octree must be exactly deep enough to hold `size`, as with [`Octree::depth_for_size`].
+///
+/// Files written by older versions are migrated on load. After the `version` and `flags` fields,
+/// version 1 files hold the resolution and size each padded to 16 bytes, the palette, and the
+/// uncompressed octree pool: grids of nine `u32` cells, eight children followed by an LOD cell.
+/// A cell holds its type in the low two bits (empty, grid pointer or material) and a pool index
+/// or 24-bit color above them. They can't hold attachments or metadata, and their octree is
+/// rebuilt from the voxels it describes.
+#[derive(Debug, Clone)]
+pub struct C2vFile {
+    pub volume: VoxelVolume,
+    pub metadata: BTreeMap<String, String>
+}
+
+#[derive(Debug)]
+pub enum C2vError {
+    InvalidHeader,
+    /// A file written by a newer version than [`C2V_VERSION`].
+    UnsupportedVersion(u16),
+    UnexpectedEof,
+    InvalidOctree,
+    InvalidMetadata,
+    /// Bytes past the end of the last section.
+    TrailingBytes
+}
+
+impl fmt::Display for C2vError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            C2vError::InvalidHeader => write!(f, "not a craft2 volume file"),
+            C2vError::UnsupportedVersion(version) => write!(f, "unsupported volume file version {}", version),
+            C2vError::UnexpectedEof => write!(f, "unexpected end of file"),
+            C2vError::InvalidOctree => write!(f, "invalid octree data"),
+            C2vError::InvalidMetadata => write!(f, "invalid metadata"),
+            C2vError::TrailingBytes => write!(f, "unexpected bytes after the end of the file")
+        }
+    }
+}
+
+impl std::error::Error for C2vError {}
+
+impl ReadError for C2vError {
+    fn unexpected_eof() -> C2vError {
+        C2vError::UnexpectedEof
+    }
+}
+
+impl C2vFile {
+    pub fn parse(bytes: &[u8]) -> Result<C2vFile, C2vError> {
+        let mut reader = C2vReader::new(bytes);
+        if reader.take(4)? != C2V_MAGIC {
+            return Err(C2vError::InvalidHeader);
+        }
+        let version = reader.read_u16()?;
+        let flags = reader.read_u16()?;
+
+        match version {
+            1 => C2vFile::parse_v1(reader),
+            C2V_VERSION => C2vFile::parse_v2(reader, flags),
+            0 => Err(C2vError::InvalidHeader),
+            version => Err(C2vError::UnsupportedVersion(version))
+        }
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        self.volume.write_c2v(&self.metadata, writer)
+    }
+
+    fn parse_v1(mut reader: C2vReader) -> Result<C2vFile, C2vError> {
+        let resolution = reader.read_f32()?;
+        reader.take(12)?;
+        let size = reader.read_vec3()?;
+        reader.take(4)?;
+        let palette = reader.read_palette()?;
+        validate_shape(resolution, size)?;
+
+        let pool = reader.remaining();
+        if pool.is_empty() || pool.len() % V1_GRID_LEN != 0 {
+            return Err(C2vError::InvalidOctree);
+        }
+
+        // Walks the pool from the root, refusing grids reached twice so that hostile input can
+        // neither loop nor share grids to blow up the walk.
+        let depth_max = Octree::depth_for_size(size.max_element().ceil() as u32);
+        let mut data = Octree::new(depth_max);
+        let mut reached = vec![false; pool.len() / V1_GRID_LEN];
+        reached[0] = true;
+        let mut pending = vec![(0usize, 0u8, [0u32; 3])];
+        while let Some((pool_index, depth, grid_min)) = pending.pop() {
+            let cell_size = 1u32 << (depth_max - depth - 1);
+            let grid = &pool[pool_index * V1_GRID_LEN..(pool_index + 1) * V1_GRID_LEN];
+            for (cell_index, cell) in grid.chunks_exact(4).take(8).enumerate() {
+                let cell = u32::from_le_bytes(cell.try_into().unwrap());
+                let cell_min = Octree::cell_min(grid_min, cell_index as u32, cell_size);
+                match (cell & 0x3, cell >> 2) {
+                    (0, _) => {},
+                    (1, child) if depth + 1 < depth_max && !reached.get(child as usize).copied().unwrap_or(true) => {
+                        reached[child as usize] = true;
+                        pending.push((child as usize, depth + 1, cell_min));
+                    },
+                    (2, color) if color <= 0xFFFFFF => data.fill_box(cell_min, cell_min.map(|v| v + cell_size - 1), u24_to_bytes(color)),
+                    _ => return Err(C2vError::InvalidOctree)
+                }
+            }
+        }
+
+        let mut volume = VoxelVolume::from_octree(resolution, size, data);
+        volume.palette = palette;
+
+        Ok(C2vFile {
+            volume,
+            metadata: BTreeMap::new()
+        })
+    }
+
+    fn parse_v2(mut reader: C2vReader, flags: u16) -> Result<C2vFile, C2vError> {
+        let resolution = reader.read_f32()?;
+        let size = reader.read_vec3()?;
+        let palette = reader.read_palette()?;
+        validate_shape(resolution, size)?;
+
+        let encoded_len = reader.read_u32()? as usize;
+        let compressed_len = reader.read_u32()? as usize;
+        // A byte of input never expands to more than 255 bytes of output, which bounds what a
+        // corrupt length can make us allocate.
+        if encoded_len / 255 > compressed_len {
+            return Err(C2vError::InvalidOctree);
+        }
+        let encoded = lz::decompress(reader.take(compressed_len)?, encoded_len).ok_or(C2vError::InvalidOctree)?;
+        let mut encoded = encoded.as_slice();
+        let data = Octree::read_compact(&mut encoded)
+            .filter(|data| encoded.is_empty() && data.depth_max() == Octree::depth_for_size(size.max_element().ceil() as u32))
+            .ok_or(C2vError::InvalidOctree)?;
+
+        let mut metadata = BTreeMap::new();
+        if flags & FLAG_METADATA != 0 {
+            let count = reader.read_u32()?;
+            for _ in 0..count {
+                let key = reader.read_string()?;
+                metadata.insert(key, reader.read_string()?);
+            }
+        }
+        if !reader.is_empty() {
+            return Err(C2vError::TrailingBytes);
+        }
+
+        let mut volume = VoxelVolume::from_octree(resolution, size, data);
+        volume.palette = palette;
+
+        Ok(C2vFile {
+            volume,
+            metadata
+        })
+    }
+}
+
+impl VoxelVolume {
+    /// Writes the volume as a `.c2v` file in the current [`C2V_VERSION`], with `metadata` stored
+    /// alongside it. See [`C2vFile`] for the layout.
+    pub fn write_c2v(&self, metadata: &BTreeMap<String, String>, writer: &mut impl Write) -> io::Result<()> {
+        let flags = if metadata.is_empty() { 0 } else { FLAG_METADATA };
+
+        let mut out = Vec::with_capacity(16 + 16 + 1024);
+        out.extend(C2V_MAGIC);
+        out.extend(C2V_VERSION.to_le_bytes());
+        out.extend(flags.to_le_bytes());
+        out.extend(self.resolution.to_le_bytes());
+        for side in self.size.to_array() {
+            out.extend(side.to_le_bytes());
+        }
+        for color in self.palette {
+            out.extend(color.to_le_bytes());
+        }
+
+        let mut encoded = Vec::with_capacity(self.data.packed_grid_count() * IndirectionGrid::BYTE_LEN);
+        self.data.write_compact(&mut encoded);
+        let compressed = lz::compress(&encoded);
+        out.extend((encoded.len() as u32).to_le_bytes());
+        out.extend((compressed.len() as u32).to_le_bytes());
+        out.extend(compressed);
+
+        if !metadata.is_empty() {
+            out.extend((metadata.len() as u32).to_le_bytes());
+            for (key, value) in metadata {
+                for string in [key, value] {
+                    out.extend((string.len() as u32).to_le_bytes());
+                    out.extend(string.as_bytes());
+                }
+            }
+        }
+
+        writer.write_all(&out)
+    }
+}
+
+fn validate_shape(resolution: f32, size: Vec3) -> Result<(), C2vError> {
+    if resolution.is_finite() && resolution > 0.0 && size.is_finite() && size.min_element() >= 0.0 && size.max_element() <= (1u32 << 31) as f32 {
+        Ok(())
+    } else {
+        Err(C2vError::InvalidHeader)
+    }
+}
+
+type C2vReader<'a> = ByteReader<'a, C2vError>;
+
+impl<'a> C2vReader<'a> {
+    fn read_vec3(&mut self) -> Result<Vec3, C2vError> {
+        Ok(Vec3::new(self.read_f32()?, self.read_f32()?, self.read_f32()?))
+    }
+
+    fn read_palette(&mut self) -> Result<[u32; 256], C2vError> {
+        let mut palette = [0; 256];
+        for color in &mut palette {
+            *color = self.read_u32()?;
+        }
+
+        Ok(palette)
+    }
+
+    fn read_string(&mut self) -> Result<String, C2vError> {
+        let len = self.read_u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| C2vError::InvalidMetadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{VoxelData, bytes_to_u24};
+
+    use super::*;
+
+    /// Offset of the octree section of a version 2 file.
+    const OCTREE_OFFSET: usize = 4 + 2 + 2 + 4 + 12 + 256 * 4;
+
+    fn sample() -> VoxelVolume {
+        let mut volume = VoxelVolume::with_resolution([40, 12, 33], 8);
+        for (index, color) in volume.palette.iter_mut().enumerate() {
+            *color = 0x01020300 | index as u32;
+        }
+        volume.data.fill_box([0, 0, 0], [15, 7, 15], u24_to_bytes(3));
+        volume.data.add_data(39, 11, 32, u24_to_bytes(200));
+        volume.data.add_data(20, 5, 9, u24_to_bytes(7));
+        volume.data.remove_data(20, 5, 9);
+
+        volume
+    }
+
+    fn write(volume: &VoxelVolume, metadata: &BTreeMap<String, String>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        volume.write_c2v(metadata, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let volume = sample();
+        let metadata = BTreeMap::from([("author".to_string(), "someone".to_string()), ("empty".to_string(), String::new())]);
+
+        for metadata in [BTreeMap::new(), metadata] {
+            let file = C2vFile::parse(&write(&volume, &metadata)).unwrap();
+            assert_eq!(file.metadata, metadata);
+            assert_eq!(file.volume.resolution, volume.resolution);
+            assert_eq!(file.volume.size, volume.size);
+            assert_eq!(file.volume.palette, volume.palette);
+            assert_eq!(file.volume.data.to_bytes(), volume.data.to_bytes());
+        }
+    }
+
+    #[test]
+    fn version_1_is_migrated() {
+        let mut bytes = C2V_MAGIC.to_vec();
+        bytes.extend(1u16.to_le_bytes());
+        bytes.extend(0u16.to_le_bytes());
+        for value in [0.25f32, 0.0, 0.0, 0.0, 4.0, 4.0, 4.0, 0.0] {
+            bytes.extend(value.to_le_bytes());
+        }
+        for index in 0..256u32 {
+            bytes.extend((0xff000000 | index).to_le_bytes());
+        }
+        // A 4x4x4 octree: the root points its first cell at grid 1 and fills its last, grid 1
+        // holds two single voxels.
+        let pointer = |pool_index: u32| 1 | pool_index << 2;
+        let material = |color: u32| 2 | color << 2;
+        for cells in [[pointer(1), 0, 0, 0, 0, 0, 0, material(5), 0], [material(7), 0, 0, material(9), 0, 0, 0, 0, 0]] {
+            for cell in cells {
+                bytes.extend(cell.to_le_bytes());
+            }
+        }
+
+        let file = C2vFile::parse(&bytes).unwrap();
+        assert!(file.metadata.is_empty());
+        assert_eq!(file.volume.resolution, 0.25);
+        assert_eq!(file.volume.size, Vec3::splat(4.0));
+        assert_eq!(file.volume.palette[9], 0xff000009);
+        assert_eq!(file.volume.data.depth_max(), 2);
+
+        let mut voxels: Vec<_> = file.volume.data.iter().map(|(x, y, z, data)| ([x, y, z], bytes_to_u24(data))).collect();
+        voxels.sort_unstable();
+        let mut expected = vec![([0, 0, 0], 7), ([1, 1, 0], 9)];
+        for z in 2..4 {
+            for y in 2..4 {
+                for x in 2..4 {
+                    expected.push(([x, y, z], 5));
+                }
+            }
+        }
+        expected.sort_unstable();
+        assert_eq!(voxels, expected);
+        assert_eq!(file.volume.data.get(3, 3, 3), Some(VoxelData::Material(u24_to_bytes(5))));
+
+        // A pointer back to the root, and one past the end of the pool.
+        for pool_index in [0, 2] {
+            let mut corrupt = bytes.clone();
+            let cell = bytes.len() - 2 * V1_GRID_LEN;
+            corrupt[cell..cell + 4].copy_from_slice(&pointer(pool_index).to_le_bytes());
+            assert!(matches!(C2vFile::parse(&corrupt), Err(C2vError::InvalidOctree)));
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let metadata = BTreeMap::from([("key".to_string(), "value".to_string())]);
+        let bytes = write(&sample(), &metadata);
+        for len in 0..bytes.len() {
+            assert!(C2vFile::parse(&bytes[..len]).is_err());
+        }
+
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert!(matches!(C2vFile::parse(&trailing), Err(C2vError::TrailingBytes)));
+    }
+
+    #[test]
+    fn rejects_out_of_range_back_references() {
+        let bytes = write(&sample(), &BTreeMap::new());
+        let encoded_len = u32::from_le_bytes(bytes[OCTREE_OFFSET..OCTREE_OFFSET + 4].try_into().unwrap());
+
+        // An octree section whose first sequence copies from before the start of the output.
+        let mut corrupt = bytes[..OCTREE_OFFSET].to_vec();
+        let compressed = [0x1f, 1, 2, 0, 255, 255, 255];
+        corrupt.extend(encoded_len.to_le_bytes());
+        corrupt.extend((compressed.len() as u32).to_le_bytes());
+        corrupt.extend(compressed);
+        assert!(matches!(C2vFile::parse(&corrupt), Err(C2vError::InvalidOctree)));
+    }
+}
@@ -0,0 +1,216 @@
+//! A small LZ77 compressor for the sections of our own file formats, laid out like an LZ4 block:
+//! a sequence of `token, [literal length], literals, offset: u16, [match length]`, where the
+//! token holds the literal length in its high nibble and the match length minus
+//! [`MIN_MATCH`] in its low nibble. A nibble of 15 is extended by the following bytes, each
+//! added to it, until one is below 255. The last sequence holds only literals.
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 14;
+
+pub(crate) fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2);
+    // Holds the last position seen with each hash, plus one so zero means none.
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let mut literal_start = 0;
+    let mut position = 0;
+
+    while position + MIN_MATCH <= input.len() {
+        let key = hash(&input[position..position + MIN_MATCH]);
+        let candidate = table[key];
+        table[key] = position + 1;
+
+        if candidate > 0 && position - (candidate - 1) <= MAX_OFFSET {
+            let candidate = candidate - 1;
+            if input[candidate..candidate + MIN_MATCH] == input[position..position + MIN_MATCH] {
+                let mut len = MIN_MATCH;
+                while position + len < input.len() && input[candidate + len] == input[position + len] {
+                    len += 1;
+                }
+
+                write_sequence(&mut out, &input[literal_start..position], Some((position - candidate, len)));
+                position += len;
+                literal_start = position;
+                continue;
+            }
+        }
+
+        position += 1;
+    }
+
+    write_sequence(&mut out, &input[literal_start..], None);
+
+    out
+}
+
+/// Decompresses the output of [`compress`], which must come out at exactly `len` bytes.
+pub(crate) fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut position = 0;
+
+    loop {
+        let token = *input.get(position)?;
+        position += 1;
+
+        let mut literal_len = (token >> 4) as usize;
+        if literal_len == 15 {
+            literal_len += read_length(input, &mut position)?;
+        }
+        let literals = input.get(position..position.checked_add(literal_len)?)?;
+        if out.len() + literal_len > len {
+            return None;
+        }
+        out.extend_from_slice(literals);
+        position += literal_len;
+
+        if position == input.len() {
+            break;
+        }
+
+        let offset = u16::from_le_bytes([*input.get(position)?, *input.get(position + 1)?]) as usize;
+        position += 2;
+        let mut match_len = (token & 0xf) as usize;
+        if match_len == 15 {
+            match_len += read_length(input, &mut position)?;
+        }
+        match_len += MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + match_len > len {
+            return None;
+        }
+
+        // Matches may overlap the bytes they produce, so copy one byte at a time.
+        let start = out.len() - offset;
+        for index in start..start + match_len {
+            out.push(out[index]);
+        }
+    }
+
+    if out.len() == len {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (value.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], found: Option<(usize, usize)>) {
+    let match_len = found.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literals.len().min(15) as u8) << 4) | match_len.min(15) as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+
+    if let Some((offset, _)) = found {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            write_length(out, match_len - 15);
+        }
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn read_length(input: &[u8], position: &mut usize) -> Option<usize> {
+    let mut len = 0usize;
+    loop {
+        let byte = *input.get(*position)?;
+        *position += 1;
+        len = len.checked_add(byte as usize)?;
+        if byte < 255 {
+            return Some(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let compressed = compress(input);
+        assert_eq!(decompress(&compressed, input.len()).as_deref(), Some(input));
+        compressed
+    }
+
+    /// Bytes from a xorshift generator, which don't compress.
+    fn noise(seed: u32, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn short_inputs() {
+        assert_eq!(round_trip(&[]), [0]);
+        round_trip(&[7]);
+        round_trip(&[1, 2, 3]);
+        round_trip(&[1, 2, 3, 4]);
+        round_trip(&[9; 5]);
+    }
+
+    #[test]
+    fn incompressible_input() {
+        let input = noise(1, 20_000);
+        // Only the literal lengths are added.
+        assert!(round_trip(&input).len() <= input.len() + input.len() / 255 + 16);
+    }
+
+    #[test]
+    fn long_matches() {
+        assert!(round_trip(&[0; 100_000]).len() < 500);
+
+        let pattern = noise(2, 1000);
+        let repeated: Vec<u8> = pattern.iter().cycle().take(70_000).copied().collect();
+        assert!(round_trip(&repeated).len() < 2000);
+
+        // Runs of literals of every length between matches, then a repeat further back than an
+        // offset can reach.
+        let mut mixed = pattern.clone();
+        for len in 0..300 {
+            mixed.extend(noise(len as u32 + 3, len));
+            mixed.extend(&pattern[..len.min(40)]);
+        }
+        mixed.extend(noise(4, MAX_OFFSET));
+        mixed.extend(&pattern);
+        round_trip(&mixed);
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let input: Vec<u8> = noise(5, 300).into_iter().chain([5; 300]).chain(noise(6, 300)).collect();
+        let compressed = round_trip(&input);
+        for len in 0..compressed.len() {
+            assert_eq!(decompress(&compressed[..len], input.len()), None);
+        }
+        assert_eq!(decompress(&compressed, input.len() - 1), None);
+        assert_eq!(decompress(&compressed, input.len() + 1), None);
+    }
+
+    #[test]
+    fn rejects_out_of_range_matches() {
+        // A match before any output, a match with offset 0, and a match reaching past the start.
+        assert_eq!(decompress(&[0x00, 1, 0, 0x00], 4), None);
+        assert_eq!(decompress(&[0x10, 7, 0, 0, 0x00], 5), None);
+        assert_eq!(decompress(&[0x20, 7, 8, 3, 0, 0x00], 6), None);
+        // A match running past the expected length.
+        assert_eq!(decompress(&[0x1f, 7, 1, 0, 255, 255, 0x00], 100), None);
+        assert_eq!(decompress(&[0x10, 7, 1, 0, 0x00], 5).unwrap(), [7; 5]);
+    }
+}
@@ -0,0 +1,22 @@
+mod c2v;
+mod lz;
+mod palette;
+mod qb;
+mod reader;
+mod vox;
+mod voxelize;
+
+pub use self::{
+    c2v::*,
+    palette::*,
+    qb::*,
+    vox::*,
+    voxelize::*
+};
+
+/// Scene graphs and node hierarchies deeper than this are rejected as malformed or cyclic.
+const MAX_NODE_DEPTH: usize = 64;
+
+/// The most nodes visited when building one file's scene. Nodes can be listed as children any
+/// number of times, so a small file could otherwise expand into exponentially many.
+const MAX_SCENE_NODES: usize = 1 << 16;
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+/// Collects true colors, packed the way [`crate::color_to_rgba_u32`] does, and reduces them to a
+/// [`crate::VoxelVolume`] palette.
+#[derive(Debug, Clone, Default)]
+pub struct PaletteBuilder {
+    counts: HashMap<u32, u32>
+}
+
+/// A palette of at most 256 colors built by [`PaletteBuilder::build`], and the entry each
+/// collected color maps to.
+#[derive(Debug, Clone)]
+pub struct ReducedPalette {
+    pub palette: [u32; 256],
+    /// The number of entries of `palette` in use.
+    pub len: usize,
+    indices: HashMap<u32, u8>
+}
+
+impl PaletteBuilder {
+    pub fn new() -> PaletteBuilder {
+        PaletteBuilder::default()
+    }
+
+    /// Adds one voxel of `color`. Colors used by more voxels get more precise palette entries.
+    pub fn add(&mut self, color: u32) {
+        self.add_count(color, 1);
+    }
+
+    /// Adds `count` voxels of `color` at once.
+    pub fn add_count(&mut self, color: u32, count: u32) {
+        let total = self.counts.entry(color).or_insert(0);
+        *total = total.saturating_add(count);
+    }
+
+    /// Builds the palette. Up to 256 distinct colors are kept exactly; more are reduced with
+    /// median cut, repeatedly splitting the set of colors with the widest channel range at its
+    /// weighted median, and giving each set the average of its colors.
+    pub fn build(&self) -> ReducedPalette {
+        let mut colors: Vec<(u32, u32)> = self.counts.iter().map(|(color, count)| (*color, *count)).collect();
+        colors.sort_unstable();
+
+        // Each set of colors is a range of `colors`, with its widest channel and that channel's extent.
+        let mut boxes: Vec<_> = if colors.len() <= 256 {
+            (0..colors.len()).map(|index| (index..index + 1, (0, 0))).collect()
+        } else {
+            vec![(0..colors.len(), widest_channel(&colors))]
+        };
+        // Singletons never split, so this only runs with more than 256 colors.
+        while boxes.len() < 256 {
+            let widest = boxes.iter()
+                .enumerate()
+                .filter(|(_, (range, _))| range.len() > 1)
+                .max_by_key(|(_, (_, (_, extent)))| *extent)
+                .map(|(index, (_, (channel, _)))| (index, *channel));
+            let (index, channel) = match widest {
+                Some(widest) => widest,
+                None => break
+            };
+
+            let range = boxes[index].0.clone();
+            let slice = &mut colors[range.clone()];
+            slice.sort_unstable_by_key(|(color, _)| channel_value(*color, channel));
+
+            let total: u64 = slice.iter().map(|(_, count)| u64::from(*count)).sum();
+            let mut below = 0;
+            let mut split = 1;
+            for (offset, (_, count)) in slice.iter().enumerate().take(slice.len() - 1) {
+                below += u64::from(*count);
+                split = offset + 1;
+                if below * 2 >= total {
+                    break;
+                }
+            }
+
+            let (lower, upper) = (range.start..range.start + split, range.start + split..range.end);
+            boxes[index] = (lower.clone(), widest_channel(&colors[lower]));
+            boxes.push((upper.clone(), widest_channel(&colors[upper])));
+        }
+
+        let mut palette = [0; 256];
+        let mut indices = HashMap::with_capacity(colors.len());
+        for (index, (range, _)) in boxes.iter().enumerate() {
+            let slice = &colors[range.clone()];
+            let total: u64 = slice.iter().map(|(_, count)| u64::from(*count)).sum::<u64>().max(1);
+            let mut channels = [0u8; 4];
+            for (channel, average) in channels.iter_mut().enumerate() {
+                let sum: u64 = slice.iter().map(|(color, count)| u64::from(channel_value(*color, channel)) * u64::from(*count)).sum();
+                *average = ((sum + total / 2) / total) as u8;
+            }
+            palette[index] = u32::from_be_bytes(channels);
+
+            for (color, _) in slice {
+                indices.insert(*color, index as u8);
+            }
+        }
+
+        ReducedPalette {
+            palette,
+            len: boxes.len(),
+            indices
+        }
+    }
+}
+
+impl ReducedPalette {
+    /// The palette entry for `color`. Colors that weren't collected map to the closest entry.
+    pub fn index(&self, color: u32) -> u8 {
+        if let Some(index) = self.indices.get(&color) {
+            return *index;
+        }
+
+        let distance = |entry: u32| (0..4)
+            .map(|channel| (i32::from(channel_value(color, channel)) - i32::from(channel_value(entry, channel))).pow(2))
+            .sum::<i32>();
+        (0..self.len.max(1))
+            .min_by_key(|index| distance(self.palette[*index]))
+            .unwrap_or(0) as u8
+    }
+}
+
+/// Channel 0 to 3: red, green, blue, alpha.
+fn channel_value(color: u32, channel: usize) -> u8 {
+    color.to_be_bytes()[channel]
+}
+
+/// The channel the colors spread the furthest along, and how far.
+fn widest_channel(colors: &[(u32, u32)]) -> (usize, u8) {
+    (0..4)
+        .map(|channel| {
+            let values = colors.iter().map(|(color, _)| channel_value(*color, channel));
+            let min = values.clone().min().unwrap_or(0);
+            let max = values.max().unwrap_or(0);
+            (channel, max - min)
+        })
+        .max_by_key(|(channel, extent)| (*extent, std::cmp::Reverse(*channel)))
+        .unwrap()
+}
@@ -0,0 +1,378 @@
+use std::{fmt, io::{self, Write}};
+
+use bevy::{asset::{AssetLoader, LoadContext, LoadedAsset}, math::Vec3, prelude::Transform, utils::BoxedFuture};
+
+use crate::{VoxelVolume, VoxelScene, VoxelSceneNode, Octree, PaletteBuilder, u24_to_bytes, bytes_to_u24};
+
+use super::reader::{ByteReader, ReadError};
+
+/// The format version Qubicle writes, 1.1.0.0.
+const QB_VERSION: [u8; 4] = [1, 1, 0, 0];
+
+/// In a compressed slice, introduces a run: a count and the color repeated.
+const QB_CODE_FLAG: u32 = 2;
+/// In a compressed slice, ends the slice.
+const QB_NEXT_SLICE_FLAG: u32 = 6;
+
+/// Matrices larger than this along an axis are rejected.
+pub const QB_MAX_MATRIX_SIZE: u32 = 1024;
+/// Matrices holding more voxels than this are rejected, whatever the size of each side.
+pub const QB_MAX_MATRIX_VOXELS: u64 = 1 << 24;
+/// The most voxels the matrices of one file may hold together.
+const QB_MAX_FILE_VOXELS: u64 = 1 << 26;
+
+/// Loads Qubicle `.qb` files. The first matrix is the default asset, every matrix is also labeled
+/// `Matrix<n>`, and a scene placing them at their offsets is labeled `Scene`.
+#[derive(Default)]
+pub struct QbLoader;
+
+impl AssetLoader for QbLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let qb = QbFile::parse(bytes)?;
+            let roots = qb.scene_roots();
+
+            load_context.set_default_asset(LoadedAsset::new(qb.matrices[0].volume.clone()));
+            let volumes = qb.matrices.into_iter()
+                .enumerate()
+                .map(|(index, matrix)| load_context.set_labeled_asset(&format!("Matrix{}", index), LoadedAsset::new(matrix.volume)))
+                .collect();
+            load_context.set_labeled_asset("Scene", LoadedAsset::new(VoxelScene {
+                volumes,
+                roots
+            }));
+
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["qb"]
+    }
+}
+
+/// The matrices of a Qubicle `.qb` file.
+///
+/// Qubicle stores a true color per voxel, so on load every matrix shares a palette reduced from
+/// the colors of all of them with [`PaletteBuilder`], and a voxel's material is its palette index.
+/// Files with a left-handed z axis are mirrored along z into our right-handed frame.
+#[derive(Debug, Clone)]
+pub struct QbFile {
+    pub matrices: Vec<QbMatrix>
+}
+
+#[derive(Debug, Clone)]
+pub struct QbMatrix {
+    pub name: String,
+    /// The position of the matrix's minimum corner, in voxels.
+    pub offset: [i32; 3],
+    pub volume: VoxelVolume
+}
+
+#[derive(Debug)]
+pub enum QbError {
+    UnexpectedEof,
+    NoMatrices,
+    UnsupportedColorFormat(u32),
+    InvalidMatrixSize([u32; 3]),
+    /// Matrices holding more voxels together than a file is allowed to load.
+    TooManyVoxels,
+    /// A compressed slice holding more voxels than the matrix has room for.
+    InvalidSlice { matrix: usize, slice: u32 }
+}
+
+impl fmt::Display for QbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QbError::UnexpectedEof => write!(f, "unexpected end of file"),
+            QbError::NoMatrices => write!(f, "file holds no matrices"),
+            QbError::UnsupportedColorFormat(format) => write!(f, "unsupported color format {}", format),
+            QbError::InvalidMatrixSize(size) => write!(f, "invalid matrix size {:?}", size),
+            QbError::TooManyVoxels => write!(f, "matrices hold more than {} voxels in total", QB_MAX_FILE_VOXELS),
+            QbError::InvalidSlice { matrix, slice } => write!(f, "slice {} of matrix {} overflows the matrix", slice, matrix)
+        }
+    }
+}
+
+impl std::error::Error for QbError {}
+
+impl ReadError for QbError {
+    fn unexpected_eof() -> QbError {
+        QbError::UnexpectedEof
+    }
+}
+
+/// The fields of a file header that decide how its matrices are read.
+struct QbHeader {
+    /// 0 for RGBA, 1 for BGRA.
+    color_format: u32,
+    right_handed: bool,
+    compressed: bool,
+    /// With the visibility mask encoded, alpha holds which faces are visible, or 0 for empty.
+    visibility_mask_encoded: bool,
+    matrix_count: u32
+}
+
+/// The fields preceding the voxels of a matrix.
+struct QbMatrixHeader {
+    name: String,
+    size: [u32; 3],
+    offset: [i32; 3]
+}
+
+impl QbFile {
+    pub fn parse(bytes: &[u8]) -> Result<QbFile, QbError> {
+        // Every matrix shares one palette, so a first pass counts the colors of the whole file
+        // and a second decodes each matrix into palette indices.
+        let mut palette_builder = PaletteBuilder::new();
+        let mut reader = ByteReader::new(bytes);
+        let header = QbHeader::read(&mut reader)?;
+        if header.matrix_count == 0 {
+            return Err(QbError::NoMatrices);
+        }
+        let mut voxel_count = 0;
+        for matrix in 0..header.matrix_count as usize {
+            let QbMatrixHeader { size, .. } = QbMatrixHeader::read(&mut reader)?;
+            voxel_count += size.iter().map(|side| u64::from(*side)).product::<u64>();
+            if voxel_count > QB_MAX_FILE_VOXELS {
+                return Err(QbError::TooManyVoxels);
+            }
+            header.read_voxels(&mut reader, matrix, size, |_, count, color| palette_builder.add_count(color, count as u32))?;
+        }
+        let palette = palette_builder.build();
+
+        let mut reader = ByteReader::new(bytes);
+        let header = QbHeader::read(&mut reader)?;
+        let mut matrices = Vec::new();
+        for matrix in 0..header.matrix_count as usize {
+            let QbMatrixHeader { name, size, mut offset } = QbMatrixHeader::read(&mut reader)?;
+
+            // Runs of palette indices as `(start, count, color index)`, in file order: x varying
+            // fastest, then y, then z. Neighboring runs of one color are merged.
+            let mut runs: Vec<(usize, usize, u8)> = Vec::new();
+            header.read_voxels(&mut reader, matrix, size, |start, count, color| {
+                let color_index = palette.index(color);
+                match runs.last_mut() {
+                    Some((run_start, run_count, run_color_index)) if *run_start + *run_count == start && *run_color_index == color_index => {
+                        *run_count += count;
+                    },
+                    _ => runs.push((start, count, color_index))
+                }
+            })?;
+
+            let mut volume = VoxelVolume::new(size);
+            volume.palette = palette.palette;
+            volume.data = Octree::from_fn(size, |x, y, z| {
+                let z = if header.right_handed { z } else { size[2] - 1 - z };
+                let index = x as usize + size[0] as usize * (y as usize + size[1] as usize * z as usize);
+                // The voxel is in the last run starting at or before it, if in any.
+                let (start, count, color_index) = runs[..runs.partition_point(|run| run.0 <= index)].last()?;
+                (index < start + count).then(|| u24_to_bytes(u32::from(*color_index)))
+            });
+            if !header.right_handed {
+                offset[2] = -offset[2].saturating_add(size[2] as i32);
+            }
+
+            matrices.push(QbMatrix { name, offset, volume });
+        }
+
+        Ok(QbFile { matrices })
+    }
+
+    /// A scene node per matrix, placing its volume at its offset.
+    pub fn scene_roots(&self) -> Vec<VoxelSceneNode> {
+        self.matrices.iter()
+            .enumerate()
+            .map(|(index, matrix)| {
+                let offset = Vec3::new(matrix.offset[0] as f32, matrix.offset[1] as f32, matrix.offset[2] as f32);
+                VoxelSceneNode {
+                    name: Some(matrix.name.clone()),
+                    transform: Transform::from_translation((offset + matrix.volume.size / 2.0) * matrix.volume.resolution),
+                    volume: Some(index),
+                    children: Vec::new()
+                }
+            })
+            .collect()
+    }
+
+    /// Writes the matrices as a `.qb` file with RGBA colors, a right-handed z axis and compressed
+    /// slices, which [`QbFile::parse`] reads back voxel for voxel.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if a material isn't a palette index. Voxels whose
+    /// palette entry has an alpha of zero are written opaque, as Qubicle reads them as empty.
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        write_qb(self.matrices.iter().map(|matrix| (matrix.name.as_str(), matrix.offset, &matrix.volume)), writer)
+    }
+}
+
+impl QbHeader {
+    fn read(reader: &mut ByteReader<'_, QbError>) -> Result<QbHeader, QbError> {
+        let _version = reader.read_u32()?;
+        let color_format = reader.read_u32()?;
+        if color_format > 1 {
+            return Err(QbError::UnsupportedColorFormat(color_format));
+        }
+
+        Ok(QbHeader {
+            color_format,
+            right_handed: reader.read_u32()? != 0,
+            compressed: reader.read_u32()? != 0,
+            visibility_mask_encoded: reader.read_u32()? != 0,
+            matrix_count: reader.read_u32()?
+        })
+    }
+
+    /// The color of a stored voxel value, packed as by [`crate::color_to_rgba_u32`], or `None`
+    /// if the voxel is empty.
+    fn color(&self, value: u32) -> Option<u32> {
+        let [c0, g, c2, a] = value.to_le_bytes();
+        let (r, b) = if self.color_format == 0 { (c0, c2) } else { (c2, c0) };
+        match a {
+            0 => None,
+            _ if self.visibility_mask_encoded => Some(u32::from_be_bytes([r, g, b, 255])),
+            a => Some(u32::from_be_bytes([r, g, b, a]))
+        }
+    }
+
+    /// Reads the voxels of a matrix of `size`, calling `run(start, count, color)` for every run of
+    /// `count` voxels of `color`, starting at index `start` in file order. Empty voxels and runs
+    /// are skipped.
+    fn read_voxels(
+        &self,
+        reader: &mut ByteReader<'_, QbError>,
+        matrix: usize,
+        size: [u32; 3],
+        mut run: impl FnMut(usize, usize, u32)
+    ) -> Result<(), QbError> {
+        let slice_len = size[0] as usize * size[1] as usize;
+        for z in 0..size[2] {
+            let slice_start = z as usize * slice_len;
+
+            if self.compressed {
+                let mut index = 0;
+                loop {
+                    let (count, value) = match reader.read_u32()? {
+                        QB_NEXT_SLICE_FLAG => break,
+                        QB_CODE_FLAG => (reader.read_u32()? as usize, reader.read_u32()?),
+                        value => (1, value)
+                    };
+                    if count > slice_len - index {
+                        return Err(QbError::InvalidSlice { matrix, slice: z });
+                    }
+
+                    if let Some(color) = self.color(value).filter(|_| count > 0) {
+                        run(slice_start + index, count, color);
+                    }
+                    index += count;
+                }
+            } else {
+                let slice = reader.take(slice_len * 4)?;
+                for (index, value) in slice.chunks_exact(4).enumerate() {
+                    if let Some(color) = self.color(u32::from_le_bytes(value.try_into().unwrap())) {
+                        run(slice_start + index, 1, color);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl QbMatrixHeader {
+    fn read(reader: &mut ByteReader<'_, QbError>) -> Result<QbMatrixHeader, QbError> {
+        let name_len = reader.read_u8()? as usize;
+        let name = String::from_utf8_lossy(reader.take(name_len)?).into_owned();
+        let size = [reader.read_u32()?, reader.read_u32()?, reader.read_u32()?];
+        let offset = [reader.read_i32()?, reader.read_i32()?, reader.read_i32()?];
+        if !is_supported_matrix_size(size) {
+            return Err(QbError::InvalidMatrixSize(size));
+        }
+
+        Ok(QbMatrixHeader { name, size, offset })
+    }
+}
+
+impl VoxelVolume {
+    /// Writes the volume as a `.qb` file holding a single matrix named `name`, see
+    /// [`QbFile::write`].
+    pub fn write_qb(&self, name: &str, writer: &mut impl Write) -> io::Result<()> {
+        write_qb([(name, [0, 0, 0], self)].into_iter(), writer)
+    }
+}
+
+/// Whether a matrix of `size` is within [`QB_MAX_MATRIX_SIZE`] and [`QB_MAX_MATRIX_VOXELS`].
+fn is_supported_matrix_size(size: [u32; 3]) -> bool {
+    size.iter().all(|side| (1..=QB_MAX_MATRIX_SIZE).contains(side))
+        && size.iter().map(|side| u64::from(*side)).product::<u64>() <= QB_MAX_MATRIX_VOXELS
+}
+
+fn write_qb<'a>(matrices: impl ExactSizeIterator<Item = (&'a str, [i32; 3], &'a VoxelVolume)>, writer: &mut impl Write) -> io::Result<()> {
+    let mut out = Vec::new();
+    out.extend(QB_VERSION);
+    for field in [0u32, 1, 1, 0, matrices.len() as u32] {
+        out.extend(field.to_le_bytes());
+    }
+
+    for (name, offset, volume) in matrices {
+        let mut size = [volume.size.x as u32, volume.size.y as u32, volume.size.z as u32].map(|side| side.max(1));
+        let mut voxels = Vec::new();
+        for (x, y, z, data) in volume.data.iter() {
+            let color_index = u8::try_from(bytes_to_u24(data))
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("material {:?} is not a palette index", data)))?;
+            let [r, g, b, a] = volume.palette[color_index as usize].to_be_bytes();
+            size = [size[0].max(x + 1), size[1].max(y + 1), size[2].max(z + 1)];
+            voxels.push(([x, y, z], u32::from_le_bytes([r, g, b, a.max(1)])));
+        }
+        if !is_supported_matrix_size(size) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("volume of size {:?} is too large", size)));
+        }
+
+        // Qubicle names are at most 255 bytes long.
+        let mut name_len = name.len().min(255);
+        while !name.is_char_boundary(name_len) {
+            name_len -= 1;
+        }
+        out.push(name_len as u8);
+        out.extend(&name.as_bytes()[..name_len]);
+        for side in size {
+            out.extend(side.to_le_bytes());
+        }
+        for position in offset {
+            out.extend(position.to_le_bytes());
+        }
+
+        let slice_len = size[0] as usize * size[1] as usize;
+        let mut slices = vec![Vec::new(); size[2] as usize];
+        for ([x, y, z], color) in voxels {
+            slices[z as usize].push((x as usize + y as usize * size[0] as usize, color));
+        }
+
+        let mut slice = vec![0u32; slice_len];
+        for voxels in slices {
+            slice.fill(0);
+            for (index, color) in voxels {
+                slice[index] = color;
+            }
+
+            let mut index = 0;
+            while index < slice_len {
+                let color = slice[index];
+                let run = slice[index..].iter().take_while(|value| **value == color).count();
+                if run > 2 {
+                    for value in [QB_CODE_FLAG, run as u32, color] {
+                        out.extend(value.to_le_bytes());
+                    }
+                } else {
+                    for _ in 0..run {
+                        out.extend(color.to_le_bytes());
+                    }
+                }
+                index += run;
+            }
+            out.extend(QB_NEXT_SLICE_FLAG.to_le_bytes());
+        }
+    }
+
+    writer.write_all(&out)
+}
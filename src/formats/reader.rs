@@ -0,0 +1,67 @@
+use std::marker::PhantomData;
+
+/// The error type of a format read with [`ByteReader`].
+pub(crate) trait ReadError {
+    /// The error for input that ends in the middle of a value.
+    fn unexpected_eof() -> Self;
+}
+
+/// Reads little-endian values from the front of a byte slice, failing with the error type `E` of
+/// the format being read once the bytes run out. Formats add their own compound values with an
+/// `impl ByteReader<'_, TheirError>` block.
+pub(crate) struct ByteReader<'a, E> {
+    bytes: &'a [u8],
+    error: PhantomData<fn() -> E>
+}
+
+impl<'a, E: ReadError> ByteReader<'a, E> {
+    pub(crate) fn new(bytes: &'a [u8]) -> ByteReader<'a, E> {
+        ByteReader {
+            bytes,
+            error: PhantomData
+        }
+    }
+
+    /// The bytes not read yet.
+    pub(crate) fn remaining(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], E> {
+        if len > self.bytes.len() {
+            return Err(E::unexpected_eof());
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+
+        Ok(bytes)
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8, E> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn read_u16(&mut self) -> Result<u16, E> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    pub(crate) fn read_u32(&mut self) -> Result<u32, E> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub(crate) fn read_i32(&mut self) -> Result<i32, E> {
+        Ok(i32::from_le_bytes(self.read_array()?))
+    }
+
+    pub(crate) fn read_f32(&mut self) -> Result<f32, E> {
+        Ok(f32::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], E> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+}
@@ -0,0 +1,708 @@
+use std::{collections::{BTreeMap, HashMap}, fmt, io::{self, Write}};
+
+use bevy::{asset::{AssetLoader, LoadContext, LoadedAsset}, math::{Mat3, Mat4, Vec3}, prelude::Transform, utils::BoxedFuture};
+
+use crate::{VoxelVolume, VoxelScene, VoxelSceneNode, Octree, u24_to_bytes, bytes_to_u24};
+
+use super::{MAX_NODE_DEPTH, MAX_SCENE_NODES, reader::{ByteReader, ReadError}};
+
+/// The largest model MagicaVoxel can hold along each axis.
+pub const VOX_MAX_MODEL_SIZE: u32 = 256;
+
+/// The file format version written by [`VoxelVolume::write_vox`].
+const VOX_VERSION: i32 = 150;
+
+/// Maps MagicaVoxel's z-up frame onto ours: x stays, MagicaVoxel's z becomes y, and its y becomes -z.
+const VOX_TO_WORLD: [[f32; 3]; 3] = [
+    [1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0],
+    [0.0, -1.0, 0.0]
+];
+
+/// Loads MagicaVoxel `.vox` files. The first model is the default asset, every model is also
+/// labeled `Model<n>`, and the scene graph placing them is labeled `Scene`.
+#[derive(Default)]
+pub struct VoxLoader;
+
+impl AssetLoader for VoxLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let vox = VoxFile::parse(bytes)?;
+
+            load_context.set_default_asset(LoadedAsset::new(vox.volumes[0].clone()));
+            let volumes = vox.volumes.into_iter()
+                .enumerate()
+                .map(|(index, volume)| load_context.set_labeled_asset(&format!("Model{}", index), LoadedAsset::new(volume)))
+                .collect();
+            load_context.set_labeled_asset("Scene", LoadedAsset::new(VoxelScene {
+                volumes,
+                roots: vox.roots
+            }));
+
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["vox"]
+    }
+}
+
+/// The models and scene graph of a MagicaVoxel `.vox` file.
+///
+/// Voxels are stored with MagicaVoxel's z axis as our y axis, so a model of size `[x, y, z]`
+/// becomes a volume of size `[x, z, y]`. A voxel's color index is its palette index; the `RGBA`
+/// chunk is stored shifted by one, which is undone on load.
+#[derive(Debug, Clone)]
+pub struct VoxFile {
+    /// One volume per model, all sharing the file's palette.
+    pub volumes: Vec<VoxelVolume>,
+    /// The scene graph placing the models, with `volume` indexing `volumes`. Files without one
+    /// get a root per model, placed at the origin.
+    pub roots: Vec<VoxelSceneNode>
+}
+
+#[derive(Debug)]
+pub enum VoxError {
+    InvalidHeader,
+    UnexpectedEof,
+    NoModels,
+    /// An `XYZI` chunk without the `SIZE` chunk that should precede it.
+    MissingSize,
+    InvalidModelSize([i32; 3]),
+    VoxelOutOfBounds { model: usize, voxel: [u8; 3] },
+    InvalidNode(i32),
+    /// The scene graph expands to more nodes than a file is allowed to build.
+    TooManyNodes
+}
+
+impl fmt::Display for VoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoxError::InvalidHeader => write!(f, "not a MagicaVoxel file"),
+            VoxError::UnexpectedEof => write!(f, "unexpected end of file"),
+            VoxError::NoModels => write!(f, "file holds no models"),
+            VoxError::MissingSize => write!(f, "XYZI chunk without a SIZE chunk"),
+            VoxError::InvalidModelSize(size) => write!(f, "invalid model size {:?}", size),
+            VoxError::VoxelOutOfBounds { model, voxel } => write!(f, "voxel {:?} lies outside model {}", voxel, model),
+            VoxError::InvalidNode(node) => write!(f, "invalid scene graph node {}", node),
+            VoxError::TooManyNodes => write!(f, "scene graph holds more than {} nodes", MAX_SCENE_NODES)
+        }
+    }
+}
+
+impl std::error::Error for VoxError {}
+
+impl ReadError for VoxError {
+    fn unexpected_eof() -> VoxError {
+        VoxError::UnexpectedEof
+    }
+}
+
+struct VoxModel {
+    size: [u32; 3],
+    voxels: Vec<[u8; 4]>
+}
+
+enum VoxNode {
+    Transform { attributes: HashMap<String, String>, frame: HashMap<String, String>, child: i32 },
+    Group { children: Vec<i32> },
+    Shape { models: Vec<i32> }
+}
+
+impl VoxFile {
+    pub fn parse(bytes: &[u8]) -> Result<VoxFile, VoxError> {
+        let mut reader = VoxReader::new(bytes);
+        if reader.take(4)? != b"VOX " {
+            return Err(VoxError::InvalidHeader);
+        }
+        let _version = reader.read_i32()?;
+
+        let (id, _, mut children) = reader.read_chunk()?;
+        if id != b"MAIN" {
+            return Err(VoxError::InvalidHeader);
+        }
+
+        let mut models = Vec::new();
+        let mut size = None;
+        let mut rgba = None;
+        let mut nodes = HashMap::new();
+        while !children.is_empty() {
+            let (id, mut content, _) = children.read_chunk()?;
+            match id {
+                b"SIZE" => {
+                    let model_size = [content.read_i32()?, content.read_i32()?, content.read_i32()?];
+                    if model_size.iter().any(|side| *side <= 0 || *side > VOX_MAX_MODEL_SIZE as i32) {
+                        return Err(VoxError::InvalidModelSize(model_size));
+                    }
+                    size = Some(model_size.map(|side| side as u32));
+                },
+                b"XYZI" => {
+                    let size = size.take().ok_or(VoxError::MissingSize)?;
+                    let count = content.read_len()?;
+                    let voxels = content.take(count.checked_mul(4).ok_or(VoxError::UnexpectedEof)?)?
+                        .chunks_exact(4)
+                        .map(|voxel| [voxel[0], voxel[1], voxel[2], voxel[3]])
+                        .collect();
+                    models.push(VoxModel { size, voxels });
+                },
+                b"RGBA" => {
+                    let mut palette = [[0u8; 4]; 256];
+                    for (entry, color) in palette.iter_mut().zip(content.take(256 * 4)?.chunks_exact(4)) {
+                        entry.copy_from_slice(color);
+                    }
+                    rgba = Some(palette);
+                },
+                b"nTRN" => {
+                    let node_id = content.read_i32()?;
+                    let attributes = content.read_dict()?;
+                    let child = content.read_i32()?;
+                    let _reserved = content.read_i32()?;
+                    let _layer = content.read_i32()?;
+                    let frame_count = content.read_len()?;
+                    let frame = if frame_count > 0 { content.read_dict()? } else { HashMap::new() };
+                    nodes.insert(node_id, VoxNode::Transform { attributes, frame, child });
+                },
+                b"nGRP" => {
+                    let node_id = content.read_i32()?;
+                    let _attributes = content.read_dict()?;
+                    let count = content.read_len()?;
+                    let group_children = (0..count).map(|_| content.read_i32()).collect::<Result<_, _>>()?;
+                    nodes.insert(node_id, VoxNode::Group { children: group_children });
+                },
+                b"nSHP" => {
+                    let node_id = content.read_i32()?;
+                    let _attributes = content.read_dict()?;
+                    let count = content.read_len()?;
+                    let mut shape_models = Vec::new();
+                    for _ in 0..count {
+                        shape_models.push(content.read_i32()?);
+                        let _model_attributes = content.read_dict()?;
+                    }
+                    nodes.insert(node_id, VoxNode::Shape { models: shape_models });
+                },
+                _ => {}
+            }
+        }
+
+        if models.is_empty() {
+            return Err(VoxError::NoModels);
+        }
+
+        let palette = match rgba {
+            Some(rgba) => {
+                let mut palette = [0u32; 256];
+                for (index, [r, g, b, a]) in rgba.into_iter().enumerate() {
+                    palette[(index + 1) % 256] = pack_rgba(r, g, b, a);
+                }
+                palette
+            },
+            None => default_palette()
+        };
+
+        let volumes = models.iter()
+            .enumerate()
+            .map(|(index, model)| model.to_voxel_volume(index, palette))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let roots = if nodes.contains_key(&0) {
+            let graph = VoxGraph { nodes: &nodes, models: &models, resolution: volumes[0].resolution };
+            graph.build_node(0, &mut Vec::new(), &mut 0)?.into_iter().collect()
+        } else {
+            (0..volumes.len())
+                .map(|volume| VoxelSceneNode {
+                    volume: Some(volume),
+                    ..Default::default()
+                })
+                .collect()
+        };
+
+        Ok(VoxFile {
+            volumes,
+            roots
+        })
+    }
+}
+
+impl VoxModel {
+    fn to_voxel_volume(&self, index: usize, palette: [u32; 256]) -> Result<VoxelVolume, VoxError> {
+        let [size_x, size_y, size_z] = self.size;
+        let dims = [size_x, size_z, size_y];
+
+        // Color indices by volume coordinates, with 0 left empty as in MagicaVoxel.
+        let mut dense = vec![0u8; (size_x * size_y * size_z) as usize];
+        for &[x, y, z, color_index] in &self.voxels {
+            if u32::from(x) >= size_x || u32::from(y) >= size_y || u32::from(z) >= size_z {
+                return Err(VoxError::VoxelOutOfBounds { model: index, voxel: [x, y, z] });
+            }
+
+            let [x, y, z] = [u32::from(x), u32::from(z), size_y - 1 - u32::from(y)];
+            dense[(x + dims[0] * (y + dims[1] * z)) as usize] = color_index;
+        }
+
+        let mut volume = VoxelVolume::new(dims);
+        volume.palette = palette;
+        volume.data = Octree::from_fn(dims, |x, y, z| {
+            match dense[(x + dims[0] * (y + dims[1] * z)) as usize] {
+                0 => None,
+                color_index => Some(u24_to_bytes(u32::from(color_index)))
+            }
+        });
+
+        Ok(volume)
+    }
+}
+
+impl VoxelVolume {
+    /// Writes the volume as a MagicaVoxel `.vox` file that [`VoxFile::parse`] reads back voxel for
+    /// voxel. Volumes larger than [`VOX_MAX_MODEL_SIZE`] along an axis are split into several
+    /// models, which the scene graph lines back up. Attachments are not written.
+    ///
+    /// MagicaVoxel keeps color index 0 for empty voxels, so voxels using palette entry 0 are moved
+    /// to an unused entry. Fails with [`io::ErrorKind::InvalidData`] if there is none, or if a
+    /// material isn't a palette index.
+    pub fn write_vox(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut dims = [self.size.x as u32, self.size.y as u32, self.size.z as u32];
+        let mut voxels = Vec::new();
+        let mut used = [false; 256];
+        for (x, y, z, data) in self.data.iter() {
+            let color_index = u8::try_from(bytes_to_u24(data))
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("material {:?} is not a palette index", data)))?;
+            used[color_index as usize] = true;
+            dims = [dims[0].max(x + 1), dims[1].max(y + 1), dims[2].max(z + 1)];
+            voxels.push(([x, y, z], color_index));
+        }
+
+        let mut palette = self.palette;
+        if used[0] {
+            let free_index = (1..256).find(|index| !used[*index])
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "every palette entry is used, leaving none for color index 0"))?;
+            palette[free_index] = palette[0];
+            for (_, color_index) in &mut voxels {
+                if *color_index == 0 {
+                    *color_index = free_index as u8;
+                }
+            }
+        }
+
+        // The inverse of the mapping in `VoxModel::to_voxel_volume`.
+        let size = [dims[0], dims[2], dims[1]];
+        let mut models: BTreeMap<[u32; 3], Vec<[u8; 4]>> = BTreeMap::new();
+        for ([x, y, z], color_index) in voxels {
+            let position = [x, dims[2] - 1 - z, y];
+            let model = position.map(|v| v / VOX_MAX_MODEL_SIZE);
+            let [x, y, z] = position.map(|v| (v % VOX_MAX_MODEL_SIZE) as u8);
+            models.entry(model).or_default().push([x, y, z, color_index]);
+        }
+        if models.is_empty() {
+            models.insert([0, 0, 0], Vec::new());
+        }
+
+        let mut children = Vec::new();
+        let mut shapes = Vec::new();
+        for (model, voxels) in &models {
+            let origin = model.map(|v| v * VOX_MAX_MODEL_SIZE);
+            let model_size = [0, 1, 2].map(|i| (size[i] - origin[i]).min(VOX_MAX_MODEL_SIZE));
+            let content: Vec<u8> = model_size.iter().flat_map(|v| (*v as i32).to_le_bytes()).collect();
+            write_chunk(&mut children, b"SIZE", &content);
+
+            let mut content = (voxels.len() as i32).to_le_bytes().to_vec();
+            content.extend(voxels.iter().flatten());
+            write_chunk(&mut children, b"XYZI", &content);
+
+            // MagicaVoxel places a model's voxel `floor(size / 2)` at its node's translation.
+            shapes.push([0, 1, 2].map(|i| (origin[i] + model_size[i] / 2) as i32 - (size[i] / 2) as i32));
+        }
+
+        // A root transform holding a group, holding a transform and a shape per model.
+        let mut content = Vec::new();
+        write_transform_node(&mut content, 0, 1, -1, [0, 0, 0]);
+        write_chunk(&mut children, b"nTRN", &content);
+
+        let mut content = Vec::new();
+        content.extend(1i32.to_le_bytes());
+        write_dict(&mut content, &[]);
+        content.extend((shapes.len() as i32).to_le_bytes());
+        for model in 0..shapes.len() as i32 {
+            content.extend((2 + 2 * model).to_le_bytes());
+        }
+        write_chunk(&mut children, b"nGRP", &content);
+
+        for (model, translation) in shapes.into_iter().enumerate() {
+            let node_id = 2 + 2 * model as i32;
+            let mut content = Vec::new();
+            write_transform_node(&mut content, node_id, node_id + 1, 0, translation);
+            write_chunk(&mut children, b"nTRN", &content);
+
+            let mut content = Vec::new();
+            content.extend((node_id + 1).to_le_bytes());
+            write_dict(&mut content, &[]);
+            content.extend(1i32.to_le_bytes());
+            content.extend((model as i32).to_le_bytes());
+            write_dict(&mut content, &[]);
+            write_chunk(&mut children, b"nSHP", &content);
+        }
+
+        let mut content = Vec::with_capacity(256 * 4);
+        for index in 0..256 {
+            content.extend(palette[(index + 1) % 256].to_be_bytes());
+        }
+        write_chunk(&mut children, b"RGBA", &content);
+
+        writer.write_all(b"VOX ")?;
+        writer.write_all(&VOX_VERSION.to_le_bytes())?;
+        writer.write_all(b"MAIN")?;
+        writer.write_all(&0i32.to_le_bytes())?;
+        writer.write_all(&(children.len() as i32).to_le_bytes())?;
+        writer.write_all(&children)
+    }
+}
+
+/// The parsed scene graph nodes of a file, turned into [`VoxelSceneNode`]s.
+struct VoxGraph<'a> {
+    nodes: &'a HashMap<i32, VoxNode>,
+    models: &'a [VoxModel],
+    resolution: f32
+}
+
+impl<'a> VoxGraph<'a> {
+    /// Builds the node `node_id`, or returns `None` if it is hidden. `path` holds the nodes being
+    /// built above it, which it must not lead back to, and `built` counts the nodes built so far.
+    fn build_node(&self, node_id: i32, path: &mut Vec<i32>, built: &mut usize) -> Result<Option<VoxelSceneNode>, VoxError> {
+        if path.len() >= MAX_NODE_DEPTH || path.contains(&node_id) {
+            return Err(VoxError::InvalidNode(node_id));
+        }
+        *built += 1;
+        if *built > MAX_SCENE_NODES {
+            return Err(VoxError::TooManyNodes);
+        }
+
+        let len = path.len();
+        path.push(node_id);
+        let node = self.build_node_content(node_id, path, built);
+        path.truncate(len);
+
+        node
+    }
+
+    fn build_node_content(&self, node_id: i32, path: &mut Vec<i32>, built: &mut usize) -> Result<Option<VoxelSceneNode>, VoxError> {
+        let mut node = VoxelSceneNode::default();
+        let mut rotation = Mat3::IDENTITY;
+        let (content_id, content) = match self.nodes.get(&node_id) {
+            Some(VoxNode::Transform { attributes, frame, child }) => {
+                if attributes.get("_hidden").map_or(false, |hidden| hidden == "1") {
+                    return Ok(None);
+                }
+
+                node.name = attributes.get("_name").cloned();
+                rotation = match frame.get("_r") {
+                    Some(r) => rotation_matrix(r.parse().map_err(|_| VoxError::InvalidNode(node_id))?).ok_or(VoxError::InvalidNode(node_id))?,
+                    None => Mat3::IDENTITY
+                };
+                let translation = match frame.get("_t") {
+                    Some(t) => {
+                        let t = t.split_whitespace().map(|v| v.parse::<i32>()).collect::<Result<Vec<_>, _>>().map_err(|_| VoxError::InvalidNode(node_id))?;
+                        match t[..] {
+                            [x, y, z] => Vec3::new(x as f32, y as f32, z as f32),
+                            _ => return Err(VoxError::InvalidNode(node_id))
+                        }
+                    },
+                    None => Vec3::ZERO
+                };
+                node.transform = vox_transform(rotation, translation, self.resolution);
+
+                match self.nodes.get(child) {
+                    // Transforms are checked against the path once they're built.
+                    Some(content @ VoxNode::Transform { .. }) => (*child, content),
+                    Some(_) if path.contains(child) => return Err(VoxError::InvalidNode(*child)),
+                    Some(content) => {
+                        path.push(*child);
+                        (*child, content)
+                    },
+                    None => return Err(VoxError::InvalidNode(*child))
+                }
+            },
+            Some(content) => (node_id, content),
+            None => return Err(VoxError::InvalidNode(node_id))
+        };
+
+        match content {
+            VoxNode::Transform { .. } => {
+                node.children.extend(self.build_node(content_id, path, built)?);
+            },
+            VoxNode::Group { children } => {
+                for child in children {
+                    node.children.extend(self.build_node(*child, path, built)?);
+                }
+            },
+            VoxNode::Shape { models } => {
+                for &model in models {
+                    let size = match usize::try_from(model).ok().and_then(|model| self.models.get(model)) {
+                        Some(model) => model.size,
+                        None => return Err(VoxError::InvalidNode(content_id))
+                    };
+
+                    // MagicaVoxel places a model's voxel `floor(size / 2)` at the node origin,
+                    // while volumes are centered on theirs.
+                    let center = Vec3::new(
+                        (size[0] % 2) as f32 / 2.0,
+                        (size[1] % 2) as f32 / 2.0,
+                        (size[2] % 2) as f32 / 2.0
+                    );
+                    if models.len() == 1 {
+                        node.transform.translation += vox_to_world(rotation * center) * self.resolution;
+                        node.volume = Some(model as usize);
+                    } else {
+                        node.children.push(VoxelSceneNode {
+                            transform: Transform::from_translation(vox_to_world(center) * self.resolution),
+                            volume: Some(model as usize),
+                            ..Default::default()
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(Some(node))
+    }
+}
+
+/// Converts a transform in MagicaVoxel's frame, in voxels, to one in ours, in meters.
+fn vox_transform(rotation: Mat3, translation: Vec3, resolution: f32) -> Transform {
+    let basis = Mat3::from_cols_array_2d(&VOX_TO_WORLD).transpose();
+    let rotation = basis * rotation * basis.transpose();
+    Transform::from_matrix(Mat4::from_translation(vox_to_world(translation) * resolution) * Mat4::from_mat3(rotation))
+}
+
+fn vox_to_world(v: Vec3) -> Vec3 {
+    Mat3::from_cols_array_2d(&VOX_TO_WORLD).transpose() * v
+}
+
+/// Decodes the packed rotation of an `nTRN` frame: the column of the non-zero entry of the first
+/// two rows in bits 0-1 and 2-3, and the sign of each row in bits 4-6.
+fn rotation_matrix(r: u8) -> Option<Mat3> {
+    let first = usize::from(r & 3);
+    let second = usize::from((r >> 2) & 3);
+    if first > 2 || second > 2 || first == second {
+        return None;
+    }
+    let columns = [first, second, 3 - first - second];
+
+    let mut rows = [[0.0f32; 3]; 3];
+    for (row, column) in columns.into_iter().enumerate() {
+        rows[row][column] = if (r >> (4 + row)) & 1 == 1 { -1.0 } else { 1.0 };
+    }
+
+    Some(Mat3::from_cols_array_2d(&rows).transpose())
+}
+
+/// Packs a color the way [`crate::color_to_rgba_u32`] does.
+fn pack_rgba(r: u8, g: u8, b: u8, a: u8) -> u32 {
+    u32::from_be_bytes([r, g, b, a])
+}
+
+/// The palette MagicaVoxel uses for files without an `RGBA` chunk, indexed by color index: the
+/// 6x6x6 color cube without black, then ramps of red, green, blue and gray.
+fn default_palette() -> [u32; 256] {
+    const CUBE: [u8; 6] = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    const RAMP: [u8; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let mut palette = [0u32; 256];
+    let mut index = 1;
+    for r in CUBE {
+        for g in CUBE {
+            for b in CUBE {
+                if index <= 215 {
+                    palette[index] = pack_rgba(r, g, b, 0xff);
+                    index += 1;
+                }
+            }
+        }
+    }
+
+    for channel in 0..4 {
+        for value in RAMP {
+            palette[index] = match channel {
+                0 => pack_rgba(value, 0, 0, 0xff),
+                1 => pack_rgba(0, value, 0, 0xff),
+                2 => pack_rgba(0, 0, value, 0xff),
+                _ => pack_rgba(value, value, value, 0xff)
+            };
+            index += 1;
+        }
+    }
+
+    palette
+}
+
+/// Appends a chunk without children.
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8]) {
+    out.extend(id);
+    out.extend((content.len() as i32).to_le_bytes());
+    out.extend(0i32.to_le_bytes());
+    out.extend(content);
+}
+
+fn write_string(out: &mut Vec<u8>, string: &str) {
+    out.extend((string.len() as i32).to_le_bytes());
+    out.extend(string.as_bytes());
+}
+
+fn write_dict(out: &mut Vec<u8>, dict: &[(&str, &str)]) {
+    out.extend((dict.len() as i32).to_le_bytes());
+    for (key, value) in dict {
+        write_string(out, key);
+        write_string(out, value);
+    }
+}
+
+/// Appends the content of an `nTRN` chunk with a single frame, translating by `translation`.
+fn write_transform_node(out: &mut Vec<u8>, node_id: i32, child: i32, layer: i32, translation: [i32; 3]) {
+    out.extend(node_id.to_le_bytes());
+    write_dict(out, &[]);
+    out.extend(child.to_le_bytes());
+    out.extend((-1i32).to_le_bytes());
+    out.extend(layer.to_le_bytes());
+    out.extend(1i32.to_le_bytes());
+    write_dict(out, &[("_t", &format!("{} {} {}", translation[0], translation[1], translation[2]))]);
+}
+
+type VoxReader<'a> = ByteReader<'a, VoxError>;
+
+impl<'a> VoxReader<'a> {
+    /// Reads a count, which must not be negative.
+    fn read_len(&mut self) -> Result<usize, VoxError> {
+        usize::try_from(self.read_i32()?).map_err(|_| VoxError::UnexpectedEof)
+    }
+
+    fn read_string(&mut self) -> Result<String, VoxError> {
+        let len = self.read_len()?;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    fn read_dict(&mut self) -> Result<HashMap<String, String>, VoxError> {
+        let len = self.read_len()?;
+        let mut dict = HashMap::new();
+        for _ in 0..len {
+            let key = self.read_string()?;
+            dict.insert(key, self.read_string()?);
+        }
+
+        Ok(dict)
+    }
+
+    /// Reads a chunk header, returning its id and readers over its content and children.
+    fn read_chunk(&mut self) -> Result<(&'a [u8], VoxReader<'a>, VoxReader<'a>), VoxError> {
+        let id = self.take(4)?;
+        let content_len = self.read_len()?;
+        let children_len = self.read_len()?;
+        let content = VoxReader::new(self.take(content_len)?);
+        let children = VoxReader::new(self.take(children_len)?);
+
+        Ok((id, content, children))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::Quat;
+
+    use crate::VoxelData;
+
+    use super::*;
+
+    fn write_and_parse(volume: &VoxelVolume) -> VoxFile {
+        let mut bytes = Vec::new();
+        volume.write_vox(&mut bytes).unwrap();
+        VoxFile::parse(&bytes).unwrap()
+    }
+
+    /// Every voxel of `volume` with its color, sorted.
+    fn colors(volume: &VoxelVolume) -> Vec<([u32; 3], u32)> {
+        let mut colors: Vec<_> = volume.data.iter()
+            .map(|(x, y, z, data)| ([x, y, z], volume.palette[bytes_to_u24(data) as usize]))
+            .collect();
+        colors.sort_unstable();
+        colors
+    }
+
+    /// Every voxel placed by the scene of `vox` with its color, sorted. Positions are the minimum
+    /// corners of the voxels in scene space, doubled to keep them whole for odd volume sizes.
+    fn placed_colors(vox: &VoxFile) -> Vec<([i64; 3], u32)> {
+        let mut colors = Vec::new();
+        let mut pending: Vec<_> = vox.roots.iter().map(|root| (root, Vec3::ZERO)).collect();
+        while let Some((node, parent_translation)) = pending.pop() {
+            assert_eq!(node.transform.rotation, Quat::IDENTITY);
+            let translation = parent_translation + node.transform.translation;
+            if let Some(volume) = node.volume {
+                colors.extend(colors_placed(&vox.volumes[volume], translation));
+            }
+            pending.extend(node.children.iter().map(|child| (child, translation)));
+        }
+        colors.sort_unstable();
+        colors
+    }
+
+    /// The voxels of `volume` placed by a node at `translation`, as in [`placed_colors`].
+    fn colors_placed(volume: &VoxelVolume, translation: Vec3) -> Vec<([i64; 3], u32)> {
+        colors(volume).into_iter()
+            .map(|(position, color)| {
+                let min = translation / volume.resolution + Vec3::from(position.map(|v| v as f32)) - volume.size / 2.0;
+                (min.to_array().map(|v| (v * 2.0).round() as i64), color)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn index_zero_is_remapped() {
+        let mut volume = VoxelVolume::new([8, 6, 4]);
+        for (index, color) in volume.palette.iter_mut().enumerate() {
+            *color = 0x10203000 | index as u32;
+        }
+        volume.data.add_data(0, 0, 0, u24_to_bytes(0));
+        volume.data.add_data(7, 5, 3, u24_to_bytes(0));
+        volume.data.add_data(1, 2, 3, u24_to_bytes(1));
+        volume.data.add_data(4, 1, 0, u24_to_bytes(5));
+
+        let vox = write_and_parse(&volume);
+        assert_eq!(vox.volumes.len(), 1);
+        let loaded = &vox.volumes[0];
+        assert_eq!(loaded.size, volume.size);
+        assert_eq!(colors(loaded), colors(&volume));
+
+        // MagicaVoxel can't store index 0, so those voxels take an entry no other voxel uses.
+        match loaded.data.get(0, 0, 0) {
+            Some(VoxelData::Material(data)) => assert!(![0, 1, 5].contains(&bytes_to_u24(data))),
+            other => panic!("unexpected voxel {:?}", other)
+        }
+    }
+
+    #[test]
+    fn large_volumes_are_split() {
+        let mut volume = VoxelVolume::new([300, 8, 260]);
+        for (index, color) in volume.palette.iter_mut().enumerate() {
+            *color = (index as u32) << 8 | 0xff;
+        }
+        for (index, position) in [[0, 0, 0], [299, 7, 259], [255, 3, 256], [256, 3, 255], [10, 5, 200], [260, 0, 3]].into_iter().enumerate() {
+            volume.data.add_data(position[0], position[1], position[2], u24_to_bytes(index as u32 + 1));
+        }
+        volume.data.fill_box([250, 2, 250], [262, 4, 259], u24_to_bytes(9));
+
+        // The volume is 300 by 260 by 8 in MagicaVoxel's axes, so two models by two by one.
+        let vox = write_and_parse(&volume);
+        assert_eq!(vox.volumes.len(), 4);
+
+        // With even sides, MagicaVoxel's whole-voxel centering matches ours exactly.
+        assert_eq!(placed_colors(&vox), colors_placed(&volume, Vec3::ZERO));
+    }
+
+    #[test]
+    fn empty_volume() {
+        let volume = VoxelVolume::new([5, 3, 7]);
+
+        let vox = write_and_parse(&volume);
+        assert_eq!(vox.volumes.len(), 1);
+        assert_eq!(vox.volumes[0].size, volume.size);
+        assert_eq!(vox.volumes[0].data.iter().count(), 0);
+    }
+}
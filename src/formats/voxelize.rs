@@ -0,0 +1,522 @@
+use std::{collections::HashMap, fmt};
+
+use bevy::{math::{Mat4, Vec2, Vec3, Vec4}, prelude::{Color, Image, Mesh, Transform}, render::{mesh::{PrimitiveTopology, VertexAttributeValues}, render_resource::TextureFormat}};
+
+use crate::{VoxelVolume, Octree, PaletteBuilder, u24_to_bytes};
+
+use super::{MAX_NODE_DEPTH, MAX_SCENE_NODES};
+
+/// Which voxels [`Voxelizer::voxelize`] fills.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VoxelizeMode {
+    /// Only the voxels the triangles pass through.
+    Surface,
+    /// The surface voxels and every voxel they enclose. Meshes with holes are filled up to the
+    /// holes' edges only, as the outside leaks in through them.
+    Solid
+}
+
+/// Turns triangle meshes into a [`VoxelVolume`]. Meshes are added with their colors, from
+/// Bevy [`Mesh`]es, OBJ files or glTF files, then voxelized together at a chosen resolution.
+///
+/// A voxel is colored from the closest triangle passing through it: the triangle's base color,
+/// times its interpolated vertex colors and its base color texture at the interpolated UV, if it
+/// has them. The colors of all surface voxels are then reduced to the palette with
+/// [`PaletteBuilder`].
+#[derive(Clone, Debug, Default)]
+pub struct Voxelizer {
+    triangles: Vec<Triangle>,
+    textures: Vec<Texture>
+}
+
+#[derive(Debug)]
+pub enum VoxelizeError {
+    /// A mesh that isn't a triangle list.
+    UnsupportedTopology,
+    MissingPositions,
+    /// A vertex attribute stored in a format other than `Float32x2` for UVs, `Float32x3` for
+    /// positions and `Float32x4` for colors.
+    UnsupportedAttributeFormat(&'static str),
+    UnsupportedTextureFormat(TextureFormat),
+    InvalidIndex(u32),
+    /// An OBJ line that couldn't be parsed, counting from 1.
+    InvalidObj { line: usize },
+    Gltf(gltf::Error),
+    /// A glTF node hierarchy nested too deeply to walk, as a cyclic one is.
+    NodesTooDeep,
+    /// A glTF node hierarchy expanding to more nodes than a file is allowed to visit.
+    TooManyNodes
+}
+
+impl fmt::Display for VoxelizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoxelizeError::UnsupportedTopology => write!(f, "mesh is not a triangle list"),
+            VoxelizeError::MissingPositions => write!(f, "mesh has no vertex positions"),
+            VoxelizeError::UnsupportedAttributeFormat(attribute) => write!(f, "unsupported format for vertex attribute {}", attribute),
+            VoxelizeError::UnsupportedTextureFormat(format) => write!(f, "unsupported texture format {:?}", format),
+            VoxelizeError::InvalidIndex(index) => write!(f, "vertex index {} is out of range", index),
+            VoxelizeError::InvalidObj { line } => write!(f, "invalid OBJ on line {}", line),
+            VoxelizeError::Gltf(error) => write!(f, "invalid glTF: {}", error),
+            VoxelizeError::NodesTooDeep => write!(f, "glTF node hierarchy is deeper than {} levels", MAX_NODE_DEPTH),
+            VoxelizeError::TooManyNodes => write!(f, "glTF node hierarchy holds more than {} nodes", MAX_SCENE_NODES)
+        }
+    }
+}
+
+impl std::error::Error for VoxelizeError {}
+
+#[derive(Clone, Debug)]
+struct Triangle {
+    positions: [Vec3; 3],
+    /// Linear RGBA, including the base color.
+    colors: [Vec4; 3],
+    uvs: [Vec2; 3],
+    texture: Option<usize>
+}
+
+/// A base color texture, in sRGB.
+#[derive(Clone, Debug)]
+struct Texture {
+    width: u32,
+    height: u32,
+    texels: Vec<[u8; 4]>
+}
+
+impl Voxelizer {
+    pub fn new() -> Voxelizer {
+        Voxelizer::default()
+    }
+
+    /// Adds the triangles of a triangle list `mesh`, placed by `transform`. Its vertex colors
+    /// and `base_color_texture`, sampled at its first UV set, are multiplied into `base_color`
+    /// when present.
+    pub fn add_mesh(&mut self, mesh: &Mesh, transform: Transform, base_color: Color, base_color_texture: Option<&Image>) -> Result<(), VoxelizeError> {
+        if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+            return Err(VoxelizeError::UnsupportedTopology);
+        }
+
+        let positions: Vec<Vec3> = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+            Some(VertexAttributeValues::Float32x3(positions)) => positions.iter().map(|position| Vec3::from(*position)).collect(),
+            Some(_) => return Err(VoxelizeError::UnsupportedAttributeFormat("position")),
+            None => return Err(VoxelizeError::MissingPositions)
+        };
+        let colors: Option<Vec<Vec4>> = match mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+            Some(VertexAttributeValues::Float32x4(colors)) => Some(colors.iter().map(|color| Vec4::from(*color)).collect()),
+            Some(_) => return Err(VoxelizeError::UnsupportedAttributeFormat("color")),
+            None => None
+        };
+
+        let (texture, uvs) = match base_color_texture {
+            Some(image) => {
+                let uvs: Option<Vec<Vec2>> = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+                    Some(VertexAttributeValues::Float32x2(uvs)) => Some(uvs.iter().map(|uv| Vec2::from(*uv)).collect()),
+                    Some(_) => return Err(VoxelizeError::UnsupportedAttributeFormat("uv")),
+                    None => None
+                };
+                (Some(self.add_texture(Texture::from_image(image)?)), uvs)
+            },
+            None => (None, None)
+        };
+
+        let indices: Vec<u32> = match mesh.indices() {
+            Some(indices) => indices.iter().map(|index| index as u32).collect(),
+            None => (0..positions.len() as u32).collect()
+        };
+
+        self.add_triangles(transform.compute_matrix(), &positions, &indices, colors.as_deref(), uvs.as_deref(), Vec4::from(base_color.as_linear_rgba_f32()), texture)
+    }
+
+    /// Adds the faces of a Wavefront OBJ file, colored `base_color`. Vertices may carry a color
+    /// after their position, as `v x y z r g b`. Faces with more than three vertices are split
+    /// into a fan, and materials are ignored.
+    pub fn add_obj(&mut self, obj: &str, base_color: Color) -> Result<(), VoxelizeError> {
+        let mut positions = Vec::new();
+        let mut colors = Vec::new();
+        let mut indices = Vec::new();
+
+        for (line_index, line) in obj.lines().enumerate() {
+            let invalid = || VoxelizeError::InvalidObj { line: line_index + 1 };
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("v") => {
+                    let values = fields.map(|field| field.parse::<f32>().map_err(|_| invalid())).collect::<Result<Vec<_>, _>>()?;
+                    match values[..] {
+                        [x, y, z] | [x, y, z, _] => {
+                            positions.push(Vec3::new(x, y, z));
+                            colors.push(Vec4::ONE);
+                        },
+                        [x, y, z, r, g, b] => {
+                            positions.push(Vec3::new(x, y, z));
+                            colors.push(Vec4::from(Color::rgb(r, g, b).as_linear_rgba_f32()));
+                        },
+                        _ => return Err(invalid())
+                    }
+                },
+                Some("f") => {
+                    let face = fields
+                        .map(|field| {
+                            // Only the position index matters, in `v`, `v/vt`, `v//vn` or `v/vt/vn`.
+                            let index: i64 = field.split('/').next().and_then(|index| index.parse().ok()).ok_or_else(invalid)?;
+                            let index = if index < 0 { positions.len() as i64 + index } else { index - 1 };
+                            u32::try_from(index).ok().filter(|index| (*index as usize) < positions.len()).ok_or_else(invalid)
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if face.len() < 3 {
+                        return Err(invalid());
+                    }
+
+                    for corner in 1..face.len() - 1 {
+                        indices.extend([face[0], face[corner], face[corner + 1]]);
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        self.add_triangles(Mat4::IDENTITY, &positions, &indices, Some(&colors), None, Vec4::from(base_color.as_linear_rgba_f32()), None)
+    }
+
+    /// Adds the triangle primitives of the default scene of a glTF file, `.gltf` with embedded
+    /// buffers or `.glb`, placed by their nodes. Each primitive is colored by its material's
+    /// base color factor and texture, and its vertex colors. Node hierarchies too deep or too
+    /// large to walk, as cyclic ones are, are rejected.
+    pub fn add_gltf(&mut self, bytes: &[u8]) -> Result<(), VoxelizeError> {
+        let (document, buffers, images) = gltf::import_slice(bytes).map_err(VoxelizeError::Gltf)?;
+
+        let mut textures = HashMap::new();
+        let mut nodes: Vec<(gltf::Node, Mat4, usize)> = match document.default_scene().or_else(|| document.scenes().next()) {
+            Some(scene) => scene.nodes().map(|node| (node, Mat4::IDENTITY, 0)).collect(),
+            None => Vec::new()
+        };
+        let mut visited = 0;
+        while let Some((node, parent, depth)) = nodes.pop() {
+            if depth >= MAX_NODE_DEPTH {
+                return Err(VoxelizeError::NodesTooDeep);
+            }
+            visited += 1;
+            if visited > MAX_SCENE_NODES {
+                return Err(VoxelizeError::TooManyNodes);
+            }
+
+            let transform = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+            nodes.extend(node.children().map(|child| (child, transform, depth + 1)));
+
+            let mesh = match node.mesh() {
+                Some(mesh) => mesh,
+                None => continue
+            };
+            for primitive in mesh.primitives() {
+                if primitive.mode() != gltf::mesh::Mode::Triangles {
+                    continue;
+                }
+
+                let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| data.0.as_slice()));
+                let positions: Vec<Vec3> = match reader.read_positions() {
+                    Some(positions) => positions.map(Vec3::from).collect(),
+                    None => continue
+                };
+                let indices: Vec<u32> = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect(),
+                    None => (0..positions.len() as u32).collect()
+                };
+                let colors: Option<Vec<Vec4>> = reader.read_colors(0).map(|colors| colors.into_rgba_f32().map(Vec4::from).collect());
+
+                let pbr = primitive.material().pbr_metallic_roughness();
+                let (texture, uvs) = match pbr.base_color_texture() {
+                    Some(info) => {
+                        let image = info.texture().source().index();
+                        let texture = match textures.get(&image) {
+                            Some(texture) => *texture,
+                            None => {
+                                let texture = images.get(image).and_then(Texture::from_gltf).map(|texture| self.add_texture(texture));
+                                textures.insert(image, texture);
+                                texture
+                            }
+                        };
+                        let uvs: Option<Vec<Vec2>> = reader.read_tex_coords(info.tex_coord()).map(|uvs| uvs.into_f32().map(Vec2::from).collect());
+                        (texture, uvs)
+                    },
+                    None => (None, None)
+                };
+
+                self.add_triangles(transform, &positions, &indices, colors.as_deref(), uvs.as_deref(), Vec4::from(pbr.base_color_factor()), texture)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Voxelizes every triangle added so far into a volume of `voxels_per_meter` voxels per
+    /// meter, just large enough to hold them. Also returns the transform placing the volume over
+    /// the meshes, as a [`VoxelVolume`]'s mesh is centered on it.
+    pub fn voxelize(&self, voxels_per_meter: u32, mode: VoxelizeMode) -> (VoxelVolume, Transform) {
+        let scale = voxels_per_meter as f32;
+        let (min, max) = self.triangles.iter()
+            .flat_map(|triangle| triangle.positions)
+            .fold((Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)), |(min, max), position| (min.min(position), max.max(position)));
+        if !min.is_finite() || !max.is_finite() {
+            return (VoxelVolume::with_resolution([1, 1, 1], voxels_per_meter), Transform::identity());
+        }
+
+        let origin = (min * scale).floor();
+        let dims = ((max * scale).ceil() - origin).max(Vec3::ONE).to_array().map(|side| side as u32);
+
+        // The triangle closest to each surface voxel's center, and its color there.
+        let mut surface: HashMap<[u32; 3], (f32, Vec4)> = HashMap::new();
+        for triangle in &self.triangles {
+            let positions = triangle.positions.map(|position| position * scale - origin);
+            let triangle_min = positions[0].min(positions[1]).min(positions[2]).floor().max(Vec3::ZERO);
+            let triangle_max = positions[0].max(positions[1]).max(positions[2]).floor();
+            for z in triangle_min.z as u32..=(triangle_max.z as u32).min(dims[2] - 1) {
+                for y in triangle_min.y as u32..=(triangle_max.y as u32).min(dims[1] - 1) {
+                    for x in triangle_min.x as u32..=(triangle_max.x as u32).min(dims[0] - 1) {
+                        let center = Vec3::new(x as f32, y as f32, z as f32) + Vec3::splat(0.5);
+                        if !triangle_overlaps_voxel(&positions, center) {
+                            continue;
+                        }
+
+                        let (closest, weights) = closest_point_on_triangle(&positions, center);
+                        let distance = closest.distance_squared(center);
+                        let voxel = surface.entry([x, y, z]).or_insert((f32::INFINITY, Vec4::ZERO));
+                        if distance < voxel.0 {
+                            *voxel = (distance, self.sample(triangle, weights));
+                        }
+                    }
+                }
+            }
+        }
+
+        let colors: HashMap<[u32; 3], u32> = surface.into_iter()
+            .map(|(voxel, (_, color))| {
+                // Packed as `color_to_rgba_u32` does, but rounding, so texels come out unchanged.
+                let color = color.clamp(Vec4::ZERO, Vec4::ONE);
+                let srgb = Color::rgba_linear(color.x, color.y, color.z, color.w).as_rgba_f32();
+                (voxel, u32::from_be_bytes(srgb.map(|channel| (channel * 255.0).round() as u8)))
+            })
+            .collect();
+        let mut palette_builder = PaletteBuilder::new();
+        for color in colors.values() {
+            palette_builder.add(*color);
+        }
+        let palette = palette_builder.build();
+
+        let index = |x: u32, y: u32, z: u32| x as usize + dims[0] as usize * (y as usize + dims[1] as usize * z as usize);
+        let inside = match mode {
+            VoxelizeMode::Surface => Vec::new(),
+            VoxelizeMode::Solid => {
+                // Flood the outside in from the bounds; whatever it can't reach is enclosed.
+                let mut inside = vec![true; dims.iter().map(|side| *side as usize).product()];
+                let mut pending = Vec::new();
+                for z in 0..dims[2] {
+                    for y in 0..dims[1] {
+                        for x in 0..dims[0] {
+                            let on_bounds = x == 0 || y == 0 || z == 0 || x == dims[0] - 1 || y == dims[1] - 1 || z == dims[2] - 1;
+                            if on_bounds && !colors.contains_key(&[x, y, z]) {
+                                inside[index(x, y, z)] = false;
+                                pending.push([x, y, z]);
+                            }
+                        }
+                    }
+                }
+
+                while let Some(voxel) = pending.pop() {
+                    for axis in 0..3 {
+                        for neighbor in [voxel[axis].checked_sub(1), Some(voxel[axis] + 1).filter(|side| *side < dims[axis])].into_iter().flatten() {
+                            let mut neighbor_voxel = voxel;
+                            neighbor_voxel[axis] = neighbor;
+                            let [x, y, z] = neighbor_voxel;
+                            if inside[index(x, y, z)] && !colors.contains_key(&neighbor_voxel) {
+                                inside[index(x, y, z)] = false;
+                                pending.push(neighbor_voxel);
+                            }
+                        }
+                    }
+                }
+
+                inside
+            }
+        };
+
+        let data = Octree::from_fn(dims, |x, y, z| {
+            let color = match colors.get(&[x, y, z]) {
+                Some(color) => *color,
+                // Enclosed voxels take the color of the surface before them along x, which is
+                // always there, as the outside never touches them.
+                None if mode == VoxelizeMode::Solid && inside[index(x, y, z)] => {
+                    *(0..x).rev().find_map(|x| colors.get(&[x, y, z]))?
+                },
+                None => return None
+            };
+
+            Some(u24_to_bytes(u32::from(palette.index(color))))
+        });
+
+        let resolution = 1.0 / scale;
+        let size = Vec3::new(dims[0] as f32, dims[1] as f32, dims[2] as f32);
+        let mut volume = VoxelVolume::from_octree(resolution, size, data);
+        volume.palette = palette.palette;
+
+        (volume, Transform::from_translation((origin + size / 2.0) * resolution))
+    }
+
+    fn add_texture(&mut self, texture: Texture) -> usize {
+        self.textures.push(texture);
+        self.textures.len() - 1
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn add_triangles(
+        &mut self,
+        transform: Mat4,
+        positions: &[Vec3],
+        indices: &[u32],
+        colors: Option<&[Vec4]>,
+        uvs: Option<&[Vec2]>,
+        base_color: Vec4,
+        texture: Option<usize>
+    ) -> Result<(), VoxelizeError> {
+        if let Some(index) = indices.iter().find(|index| **index as usize >= positions.len()) {
+            return Err(VoxelizeError::InvalidIndex(*index));
+        }
+
+        for corners in indices.chunks_exact(3) {
+            let corners = [corners[0] as usize, corners[1] as usize, corners[2] as usize];
+            self.triangles.push(Triangle {
+                positions: corners.map(|corner| transform.transform_point3(positions[corner])),
+                colors: corners.map(|corner| base_color * colors.and_then(|colors| colors.get(corner)).copied().unwrap_or(Vec4::ONE)),
+                uvs: corners.map(|corner| uvs.and_then(|uvs| uvs.get(corner)).copied().unwrap_or(Vec2::ZERO)),
+                // Without UVs the whole triangle would sample a single texel.
+                texture: texture.filter(|_| uvs.is_some())
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The linear color of `triangle` at the point with barycentric `weights`.
+    fn sample(&self, triangle: &Triangle, weights: Vec3) -> Vec4 {
+        let color = triangle.colors[0] * weights.x + triangle.colors[1] * weights.y + triangle.colors[2] * weights.z;
+        match triangle.texture {
+            Some(texture) => {
+                let uv = triangle.uvs[0] * weights.x + triangle.uvs[1] * weights.y + triangle.uvs[2] * weights.z;
+                color * self.textures[texture].sample(uv)
+            },
+            None => color
+        }
+    }
+}
+
+impl Texture {
+    fn from_image(image: &Image) -> Result<Texture, VoxelizeError> {
+        let format = image.texture_descriptor.format;
+        let swizzle = match format {
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => false,
+            TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => true,
+            format => return Err(VoxelizeError::UnsupportedTextureFormat(format))
+        };
+
+        Ok(Texture {
+            width: image.texture_descriptor.size.width,
+            height: image.texture_descriptor.size.height,
+            texels: image.data.chunks_exact(4)
+                .map(|texel| if swizzle { [texel[2], texel[1], texel[0], texel[3]] } else { [texel[0], texel[1], texel[2], texel[3]] })
+                .collect()
+        })
+    }
+
+    fn from_gltf(image: &gltf::image::Data) -> Option<Texture> {
+        let texels = match image.format {
+            gltf::image::Format::R8G8B8A8 => image.pixels.chunks_exact(4).map(|texel| [texel[0], texel[1], texel[2], texel[3]]).collect(),
+            gltf::image::Format::R8G8B8 => image.pixels.chunks_exact(3).map(|texel| [texel[0], texel[1], texel[2], 255]).collect(),
+            _ => return None
+        };
+
+        Some(Texture {
+            width: image.width,
+            height: image.height,
+            texels
+        })
+    }
+
+    /// The linear color of the texel at `uv`, repeating the texture outside of 0 to 1.
+    fn sample(&self, uv: Vec2) -> Vec4 {
+        let x = ((uv.x.rem_euclid(1.0) * self.width as f32) as u32).min(self.width.saturating_sub(1));
+        let y = ((uv.y.rem_euclid(1.0) * self.height as f32) as u32).min(self.height.saturating_sub(1));
+        match self.texels.get(x as usize + y as usize * self.width as usize) {
+            Some([r, g, b, a]) => Vec4::from(Color::rgba_u8(*r, *g, *b, *a).as_linear_rgba_f32()),
+            None => Vec4::ONE
+        }
+    }
+}
+
+/// Tests a triangle against the unit cube around `center` along the separating axes of the two.
+fn triangle_overlaps_voxel(positions: &[Vec3; 3], center: Vec3) -> bool {
+    let half = Vec3::splat(0.5);
+    let vertices = positions.map(|position| position - center);
+    let edges = [vertices[1] - vertices[0], vertices[2] - vertices[1], vertices[0] - vertices[2]];
+
+    let separated = |axis: Vec3| {
+        let projections = vertices.map(|vertex| vertex.dot(axis));
+        let radius = half.dot(axis.abs());
+        projections[0].min(projections[1]).min(projections[2]) > radius || projections[0].max(projections[1]).max(projections[2]) < -radius
+    };
+
+    let cross_axes = edges.iter().flat_map(|edge| [Vec3::X, Vec3::Y, Vec3::Z].map(|axis| axis.cross(*edge)));
+    let normal = edges[0].cross(edges[1]);
+    !cross_axes.chain([Vec3::X, Vec3::Y, Vec3::Z, normal]).any(separated)
+}
+
+/// The point of a triangle closest to `point`, and its barycentric weights.
+fn closest_point_on_triangle(positions: &[Vec3; 3], point: Vec3) -> (Vec3, Vec3) {
+    let [a, b, c] = *positions;
+    let ab = b - a;
+    let ac = c - a;
+    let ap = point - a;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return (a, Vec3::X);
+    }
+
+    let bp = point - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return (b, Vec3::Y);
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        let v = d1 / (d1 - d3);
+        return (a + ab * v, Vec3::new(1.0 - v, v, 0.0));
+    }
+
+    let cp = point - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return (c, Vec3::Z);
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        let w = d2 / (d2 - d6);
+        return (a + ac * w, Vec3::new(1.0 - w, 0.0, w));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+        return (b + (c - b) * w, Vec3::new(0.0, 1.0 - w, w));
+    }
+
+    let denominator = va + vb + vc;
+    if denominator == 0.0 {
+        // A degenerate triangle whose corners all lie on a line; its first corner will do.
+        return (a, Vec3::X);
+    }
+    let v = vb / denominator;
+    let w = vc / denominator;
+    (a + ab * v + ac * w, Vec3::new(1.0 - v - w, v, w))
+}
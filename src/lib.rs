@@ -1,3 +1,4 @@
+pub mod formats;
 pub mod render;
 pub mod voxel;
 // pub mod worldgen;
@@ -7,6 +8,7 @@ pub mod utility;
 use bevy::prelude::Color;
 
 pub use self::{
+    formats::*,
     render::*,
     voxel::*,
     // worldgen::*
@@ -0,0 +1,109 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::{diagnostic::{Diagnostic, Diagnostics}, asset::AssetEvent, prelude::{Handle, EventReader, Res, ResMut, Assets, Local, Time}};
+
+use crate::{VoxelVolume, VoxelVolumePlugin, OctreeStats};
+
+/// The least time between two walks over the pools of the volumes modified in the meantime, so
+/// continuous edits to a large volume don't measure it every frame.
+const STATS_REFRESH_SECONDS: f64 = 1.0;
+
+/// Octrees are at most 31 deep, so their grids sit at depths 0 to 30.
+const GRID_DEPTHS: u8 = 31;
+
+/// The last stats measured for each volume, kept by [`voxel_volume_diagnostic_system`].
+#[derive(Default)]
+pub struct VoxelVolumeStatsCache {
+    stats: HashMap<Handle<VoxelVolume>, OctreeStats>,
+    /// Volumes created or modified since their stats were last measured.
+    stale: HashSet<Handle<VoxelVolume>>,
+    last_refresh: f64,
+    /// The number of depths measured by the last frame, so depths no volume reaches any more drop
+    /// to zero.
+    measured_depths: usize
+}
+
+/// Registers the diagnostics listed on [`VoxelVolumePlugin`], including one per grid depth, if
+/// the app has diagnostics.
+pub fn setup_voxel_volume_diagnostics(diagnostics: Option<ResMut<Diagnostics>>) {
+    let mut diagnostics = match diagnostics {
+        Some(diagnostics) => diagnostics,
+        None => return
+    };
+
+    diagnostics.add(Diagnostic::new(VoxelVolumePlugin::VOLUMES, "voxel_volumes", 20));
+    diagnostics.add(Diagnostic::new(VoxelVolumePlugin::GRIDS, "voxel_grids", 20));
+    diagnostics.add(Diagnostic::new(VoxelVolumePlugin::LEAVES, "voxel_leaves", 20));
+    diagnostics.add(Diagnostic::new(VoxelVolumePlugin::PACKED_BYTES, "voxel_packed_bytes", 20).with_suffix(" bytes"));
+    diagnostics.add(Diagnostic::new(VoxelVolumePlugin::FREE_GRIDS, "voxel_free_grids", 20));
+    diagnostics.add(Diagnostic::new(VoxelVolumePlugin::COLLAPSIBLE_FRACTION, "voxel_collapsible_fraction", 20));
+    for depth in 0..GRID_DEPTHS {
+        diagnostics.add(Diagnostic::new(VoxelVolumePlugin::grids_at_depth(depth), format!("voxel_grids_depth_{}", depth), 20));
+    }
+}
+
+/// Measures every loaded [`VoxelVolume`]. The stats of a volume are only recomputed after it is
+/// created or modified, and at most once a second, so idle volumes cost nothing per frame and
+/// volumes edited every frame aren't walked every frame.
+pub fn voxel_volume_diagnostic_system(
+    diagnostics: Option<ResMut<Diagnostics>>,
+    mut events: EventReader<AssetEvent<VoxelVolume>>,
+    assets: Res<Assets<VoxelVolume>>,
+    time: Res<Time>,
+    mut cache: Local<VoxelVolumeStatsCache>
+) {
+    let mut diagnostics = match diagnostics {
+        Some(diagnostics) => diagnostics,
+        None => return
+    };
+    let cache = &mut *cache;
+
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                cache.stale.insert(handle.clone_weak());
+            }
+            AssetEvent::Removed { handle } => {
+                cache.stale.remove(handle);
+                cache.stats.remove(handle);
+            }
+        }
+    }
+
+    let now = time.seconds_since_startup();
+    if !cache.stale.is_empty() && now - cache.last_refresh >= STATS_REFRESH_SECONDS {
+        cache.last_refresh = now;
+        for handle in std::mem::take(&mut cache.stale) {
+            if let Some(voxel_volume) = assets.get(&handle) {
+                cache.stats.insert(handle, voxel_volume.data.stats());
+            }
+        }
+    }
+
+    let mut total = OctreeStats::default();
+    for stats in cache.stats.values() {
+        total.grids += stats.grids;
+        total.leaves += stats.leaves;
+        total.free_grids += stats.free_grids;
+        total.packed_bytes += stats.packed_bytes;
+        total.collapsible_grids += stats.collapsible_grids;
+        if total.depth_histogram.len() < stats.depth_histogram.len() {
+            total.depth_histogram.resize(stats.depth_histogram.len(), 0);
+        }
+        for (depth, grids) in stats.depth_histogram.iter().enumerate() {
+            total.depth_histogram[depth] += grids;
+        }
+    }
+
+    diagnostics.add_measurement(VoxelVolumePlugin::VOLUMES, cache.stats.len() as f64);
+    diagnostics.add_measurement(VoxelVolumePlugin::GRIDS, total.grids as f64);
+    diagnostics.add_measurement(VoxelVolumePlugin::LEAVES, total.leaves as f64);
+    diagnostics.add_measurement(VoxelVolumePlugin::PACKED_BYTES, total.packed_bytes as f64);
+    diagnostics.add_measurement(VoxelVolumePlugin::FREE_GRIDS, total.free_grids as f64);
+    diagnostics.add_measurement(VoxelVolumePlugin::COLLAPSIBLE_FRACTION, f64::from(total.collapsible_fraction()));
+    for depth in 0..total.depth_histogram.len().max(cache.measured_depths) {
+        let grids = total.depth_histogram.get(depth).copied().unwrap_or(0);
+        diagnostics.add_measurement(VoxelVolumePlugin::grids_at_depth(depth as u8), grids as f64);
+    }
+    cache.measured_depths = total.depth_histogram.len();
+}
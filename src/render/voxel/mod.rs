@@ -1,11 +1,15 @@
 mod bundle;
+mod diagnostics;
 mod voxel;
 mod voxel_volume;
+mod voxel_scene;
 mod plugin;
 
 pub use self::{
     bundle::*,
+    diagnostics::*,
     voxel::*,
     voxel_volume::*,
+    voxel_scene::*,
     plugin::*
 };
@@ -1,6 +1,6 @@
-use bevy::{prelude::{Plugin, Assets, HandleUntyped, App, Handle, AddAsset, Msaa}, render::{render_resource::{Shader, SpecializedRenderPipelines}, extract_component::{ExtractComponentPlugin, UniformComponentPlugin}, render_asset::RenderAssetPlugin, RenderApp, RenderStage, render_phase::AddRenderCommand}, core_pipeline::{core_3d::{Opaque3d, AlphaMask3d, Transparent3d}}, reflect::TypeUuid};
+use bevy::{diagnostic::DiagnosticId, prelude::{Plugin, Assets, HandleUntyped, App, Handle, AddAsset, Msaa}, render::{render_resource::{Shader, SpecializedRenderPipelines}, extract_component::{ExtractComponentPlugin, UniformComponentPlugin}, render_asset::RenderAssets, RenderApp, RenderStage, render_phase::AddRenderCommand}, core_pipeline::{core_3d::{Opaque3d, AlphaMask3d, Transparent3d}}, reflect::TypeUuid};
 
-use crate::{VoxelVolume, DrawVoxels, VoxelPipeline, DEFAULT_VOXEL_VOLUME_HANDLE, VoxelVolumeUniform};
+use crate::{VoxelVolume, VoxelScene, VoxLoader, C2vLoader, QbLoader, DrawVoxels, VoxelPipeline, DEFAULT_VOXEL_VOLUME_HANDLE, VoxelVolumeUniform, ExtractedVoxelVolumes};
 
 pub const VOXEL_SHADER_HANDLE: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 2557421741759925429);
@@ -12,10 +12,39 @@ pub struct VoxelVolumePlugin;
 #[derive(Default)]
 pub struct VoxelVolumeRenderPlugin;
 
+impl VoxelVolumePlugin {
+    /// The number of loaded voxel volumes.
+    pub const VOLUMES: DiagnosticId = DiagnosticId::from_u128(0x56e0a34c177445f3881f174798fa9c61);
+    /// Grids in use across all volumes, see [`crate::OctreeStats::grids`].
+    pub const GRIDS: DiagnosticId = DiagnosticId::from_u128(0xfe01045fcdc14a988c6d1674c290a06b);
+    /// Material and attachment cells across all volumes.
+    pub const LEAVES: DiagnosticId = DiagnosticId::from_u128(0x127b438f90ed462099a8bc1807acf3ef);
+    /// The size of the packed octrees uploaded for all volumes.
+    pub const PACKED_BYTES: DiagnosticId = DiagnosticId::from_u128(0xc446d750fd1b4b948fc3ec7ebeaa2649);
+    /// Released grids waiting for reuse across all volumes, see [`crate::OctreeStats::free_grids`].
+    pub const FREE_GRIDS: DiagnosticId = DiagnosticId::from_u128(0x8d1f3b0e2a6c4f7d9e51c0b7a3f4d286);
+    /// The fraction of the grids in use across all volumes that could be collapsed, see
+    /// [`crate::OctreeStats::collapsible_fraction`].
+    pub const COLLAPSIBLE_FRACTION: DiagnosticId = DiagnosticId::from_u128(0x3e7a9c514b2d4e08a6f1d92c5b0e7a13);
+
+    /// Grids in use at `depth` across all volumes, a bin of [`crate::OctreeStats::depth_histogram`].
+    /// Only depths some volume has reached are measured.
+    pub const fn grids_at_depth(depth: u8) -> DiagnosticId {
+        DiagnosticId::from_u128(0x6b2f0d8e1c9a4a3fb7e45d10c8f2a900 + depth as u128)
+    }
+}
+
 impl Plugin for VoxelVolumePlugin {
     fn build(&self, app: &mut App) {
         
-        app.add_asset::<VoxelVolume>();
+        app.add_asset::<VoxelVolume>()
+            .add_asset::<VoxelScene>()
+            .init_asset_loader::<VoxLoader>()
+            .init_asset_loader::<C2vLoader>()
+            .init_asset_loader::<QbLoader>();
+
+        app.add_startup_system(super::diagnostics::setup_voxel_volume_diagnostics)
+            .add_system(super::diagnostics::voxel_volume_diagnostic_system);
     }
 }
 
@@ -34,8 +63,7 @@ impl Plugin for VoxelVolumeRenderPlugin {
 
         app.insert_resource(Msaa { samples: 1 });
 
-        app.add_plugin(ExtractComponentPlugin::<Handle<VoxelVolume>>::default())
-            .add_plugin(RenderAssetPlugin::<VoxelVolume>::default());
+        app.add_plugin(ExtractComponentPlugin::<Handle<VoxelVolume>>::default());
 
         app.world
             .get_resource_mut::<Assets<VoxelVolume>>()
@@ -53,6 +81,11 @@ impl Plugin for VoxelVolumeRenderPlugin {
             // .add_render_command::<AlphaMask3d, DrawVoxels>()
             .init_resource::<VoxelPipeline>()
             .init_resource::<SpecializedRenderPipelines<VoxelPipeline>>()
+            // Stands in for `RenderAssetPlugin` so edits upload only the grids they touched.
+            .init_resource::<RenderAssets<VoxelVolume>>()
+            .init_resource::<ExtractedVoxelVolumes>()
+            .add_system_to_stage(RenderStage::Extract, super::voxel_volume::extract_voxel_volume_assets)
+            .add_system_to_stage(RenderStage::Prepare, super::voxel_volume::prepare_voxel_volume_assets)
             .add_system_to_stage(RenderStage::Extract, super::voxel::extract_voxel_volumes)
             .add_system_to_stage(RenderStage::Queue, super::voxel::queue_voxel_volume_view_bind_groups)
             .add_system_to_stage(RenderStage::Queue, super::voxel::queue_voxel_volume_uniform_bind_groups)
@@ -23,7 +23,9 @@ struct GridCell {
 };
 
 struct IndirectionGrid {
-    cells: array<GridCell, 8>
+    cells: array<GridCell, 8>,
+    // The most common material below this grid, see `IndirectionGrid::lod`.
+    lod: u32
 };
 
 struct VoxelVolume {
@@ -79,26 +81,92 @@ let COLOR_GREEN_MASK = 0x00FF0000u;
 let COLOR_BLUE_MASK = 0x0000FF00u;
 let COLOR_ALPHA_MASK = 0x000000FFu;
 
-let CELL_TYPE_MASK: u32 = 0x000000FFu;
-let CELL_DATA_MASK: u32 = 0xFFFFFF00u;
+// Cells are packed as (payload << 2) | type, see `GridCell::to_u32`.
+let CELL_TYPE_MASK: u32 = 0x00000003u;
+let CELL_DATA_SHIFT: u32 = 2u;
+
+// Deepest octree the traversal stack can hold. Keep in sync with the stack size in `trace_voxel`.
+let MAX_STACK_DEPTH: u32 = 16u;
 
 let CELL_TYPE_GRID_POINTER = 1u;
 let CELL_TYPE_DATA = 2u;
 let CELL_TYPE_EMPTY = 0u;
+let CELL_TYPE_ATTACHMENT = 3u;
+
+// Attachment payloads are packed as (root pool index << 5) | orientation index.
+let ATTACHMENT_ORIENTATION_MASK: u32 = 0x0000001Fu;
+let ATTACHMENT_ROOT_SHIFT: u32 = 5u;
 
 struct Stack {
     pool_index: u32,
     grid_index: u32,
     depth: u32,
     center: vec3<f32>,
+    // Rotation of the (possibly attached) octree being traversed, applied to child cell offsets.
+    rotation: mat3x3<f32>,
 };
 
+fn quarter_turn(axis: vec3<f32>, v: vec3<f32>) -> vec3<f32> {
+    return cross(axis, v) + axis * dot(axis, v);
+}
+
+// Keep in sync with `Orientation::matrix`. The result is conjugated by the z flip baked into the
+// cell offsets used by `trace_voxel`.
+fn orientation_matrix(orientation: u32) -> mat3x3<f32> {
+    var axis = vec3<f32>(0.0, 1.0, 0.0);
+    var m = mat3x3<f32>(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(0.0, 0.0, 1.0));
+
+    switch (orientation / 4u) {
+        case 0u: {
+            axis = vec3<f32>(1.0, 0.0, 0.0);
+            m = mat3x3<f32>(vec3<f32>(0.0, -1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 0.0, 1.0));
+        }
+        case 1u: {
+            axis = vec3<f32>(-1.0, 0.0, 0.0);
+            m = mat3x3<f32>(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(-1.0, 0.0, 0.0), vec3<f32>(0.0, 0.0, 1.0));
+        }
+        case 3u: {
+            axis = vec3<f32>(0.0, -1.0, 0.0);
+            m = mat3x3<f32>(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, -1.0, 0.0), vec3<f32>(0.0, 0.0, -1.0));
+        }
+        case 4u: {
+            axis = vec3<f32>(0.0, 0.0, 1.0);
+            m = mat3x3<f32>(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 0.0, 1.0), vec3<f32>(0.0, -1.0, 0.0));
+        }
+        case 5u: {
+            axis = vec3<f32>(0.0, 0.0, -1.0);
+            m = mat3x3<f32>(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 0.0, -1.0), vec3<f32>(0.0, 1.0, 0.0));
+        }
+        default: {}
+    }
+
+    for (var turn: u32 = 0u; turn < orientation % 4u; turn = turn + 1u) {
+        m = mat3x3<f32>(quarter_turn(axis, m[0]), quarter_turn(axis, m[1]), quarter_turn(axis, m[2]));
+    }
+
+    let flip_z = mat3x3<f32>(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(0.0, 0.0, -1.0));
+    return flip_z * m * flip_z;
+}
+
+fn palette_color(cell: u32) -> vec4<f32> {
+    let palette_index = cell >> CELL_DATA_SHIFT;
+    let rgba = voxel_volume.palette[palette_index];
+
+    let alpha = f32(rgba & COLOR_ALPHA_MASK) / 255.0;
+    let blue = f32((rgba & COLOR_BLUE_MASK) >> 8u) / 255.0;
+    let green = f32((rgba & COLOR_GREEN_MASK) >> 16u) / 255.0;
+    let red = f32((rgba & COLOR_RED_MASK) >> 24u) / 255.0;
+
+    return vec4<f32>(red, green, blue, alpha);
+}
+
 struct TraceResult {
     color: vec4<f32>,
     hit_point: vec3<f32>
 };
 
-fn trace_voxel(ray_dir: vec3<f32>, ray_position: vec3<f32>, ray_origin: vec3<f32>) -> TraceResult {
+// Grids at `lod_max_depth` and below aren't descended into; their LOD material is shaded instead.
+fn trace_voxel(ray_dir: vec3<f32>, ray_position: vec3<f32>, ray_origin: vec3<f32>, lod_max_depth: u32) -> TraceResult {
     let ray_dir_inv = 1.0 / ray_dir;
 
     var POS = array<vec3<f32>, 8>(
@@ -112,16 +180,8 @@ fn trace_voxel(ray_dir: vec3<f32>, ray_position: vec3<f32>, ray_origin: vec3<f32
         vec3<f32>(1.0, 1.0, -1.0),  
     );
     
-    var stack = array<Stack, 8>(
-        Stack(0u, 0u, 1u, vec3<f32>(0.0, 0.0, 0.0)),
-        Stack(0u, 0u, 2u, vec3<f32>(0.0, 0.0, 0.0)),
-        Stack(0u, 0u, 3u, vec3<f32>(0.0, 0.0, 0.0)),
-        Stack(0u, 0u, 4u, vec3<f32>(0.0, 0.0, 0.0)),
-        Stack(0u, 0u, 5u, vec3<f32>(0.0, 0.0, 0.0)),
-        Stack(0u, 0u, 6u, vec3<f32>(0.0, 0.0, 0.0)),
-        Stack(0u, 0u, 7u, vec3<f32>(0.0, 0.0, 0.0)),
-        Stack(0u, 0u, 8u, vec3<f32>(0.0, 0.0, 0.0))
-    );
+    var stack: array<Stack, 16>;
+    stack[0] = Stack(0u, 0u, 1u, vec3<f32>(0.0, 0.0, 0.0), mat3x3<f32>(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(0.0, 0.0, 1.0)));
 
     var color = vec4<f32>(0.0, 0.0, 0.0, 0.0);
     var hit_dist = 1000000000.0;
@@ -135,27 +195,13 @@ fn trace_voxel(ray_dir: vec3<f32>, ray_position: vec3<f32>, ray_origin: vec3<f32
         let grid_index = (*stack_entry).grid_index;
         let center = (*stack_entry).center;
         let depth = (*stack_entry).depth;
+        let rotation = (*stack_entry).rotation;
         
         let scale = 1.0 / pow(2.0, f32(depth));
         let grid = &voxel_volume.indirection_pool[pool_index];
 
-        // TODO: LOD based on distance: only traverse octree up to a decreasing depth based on distance.
-        // average colour of visited nodes so we have an average colour to use when the max depth is reached.
-        let approx_dist = (length(ray_origin - ray_position) - view.near) / view.far;
-        // let fov = 2.0 * atan(1.0 / view.view_proj[1][1]);
-        let fov = 3.14159 / 4.0; // get from view projection?
-        let pixel_scale = -approx_dist * (2.0 / view.height - 1.0) * tan(fov / 2.0) * 2.0;
-        let voxels_per_pixel = pixel_scale * (1.0 / voxel_volume.resolution);
-
-        let max_depth = log2(voxel_volume.size.x);
-        let lod_max_depth = u32(floor(max_depth - min(max((log2(voxels_per_pixel.x) + 1.0) * 2.0, 1.0), max_depth)) + 1.0);
-
-        // if (depth > lod_max_depth) {
-        //     return TraceResult(vec4<f32>(1.0, 0.0, 0.0, 1.0), ray_dir * approx_dist);
-        // }
-
         for (var curr_grid_index: u32 = grid_index; curr_grid_index < 8u; curr_grid_index = curr_grid_index + 1u) {
-            let cell_center = center + scale * POS[curr_grid_index];
+            let cell_center = center + scale * (rotation * POS[curr_grid_index]);
             var min_box = cell_center - vec3<f32>(scale);
             var max_box = cell_center + vec3<f32>(scale);
 
@@ -177,7 +223,16 @@ fn trace_voxel(ray_dir: vec3<f32>, ray_position: vec3<f32>, ray_origin: vec3<f32
                 }
                 case 1u: {
                 // case CELL_TYPE_GRID_POINTER:
-                    let next_pool_index = (cell & CELL_DATA_MASK) >> 8u;
+                    let next_pool_index = cell >> CELL_DATA_SHIFT;
+
+                    if (depth >= lod_max_depth || stack_index + 1u >= MAX_STACK_DEPTH) {
+                        let lod = voxel_volume.indirection_pool[next_pool_index].lod;
+                        if ((lod & CELL_TYPE_MASK) == CELL_TYPE_DATA) {
+                            hit_dist = intersection.distance;
+                            color = palette_color(lod);
+                        }
+                        continue;
+                    }
 
                     (*stack_entry).grid_index = curr_grid_index + 1u;
 
@@ -186,27 +241,42 @@ fn trace_voxel(ray_dir: vec3<f32>, ray_position: vec3<f32>, ray_origin: vec3<f32
                     (*next_stack_entry).grid_index = 0u;
                     (*next_stack_entry).depth = depth + 1u;
                     (*next_stack_entry).center = cell_center;
+                    (*next_stack_entry).rotation = rotation;
 
                     stack_pos = stack_pos + 2u;
                     break;
                 }
-                case 2u: {
-                // case CELL_TYPE_DATA: {
-                    let palette_index = (cell & CELL_DATA_MASK) >> 8u;
-                    let palette_color = voxel_volume.palette[palette_index];
+                case 3u: {
+                // case CELL_TYPE_ATTACHMENT:
+                    // The attached root fills this cell. Rotating its cell offsets is equivalent to
+                    // following the attachment with the ray rotated into its frame.
+                    let attachment = cell >> CELL_DATA_SHIFT;
+
+                    if (depth >= lod_max_depth || stack_index + 1u >= MAX_STACK_DEPTH) {
+                        let lod = voxel_volume.indirection_pool[attachment >> ATTACHMENT_ROOT_SHIFT].lod;
+                        if ((lod & CELL_TYPE_MASK) == CELL_TYPE_DATA) {
+                            hit_dist = intersection.distance;
+                            color = palette_color(lod);
+                        }
+                        continue;
+                    }
 
-                    let alpha = f32(palette_color & COLOR_ALPHA_MASK) / 255.0;
-                    let blue = f32((palette_color & COLOR_BLUE_MASK) >> 8u) / 255.0;
-                    let green = f32((palette_color & COLOR_GREEN_MASK) >> 16u) / 255.0;
-                    let red = f32((palette_color & COLOR_RED_MASK) >> 24u) / 255.0;
+                    (*stack_entry).grid_index = curr_grid_index + 1u;
 
+                    let next_stack_entry = &stack[stack_index + 1u];
+                    (*next_stack_entry).pool_index = attachment >> ATTACHMENT_ROOT_SHIFT;
+                    (*next_stack_entry).grid_index = 0u;
+                    (*next_stack_entry).depth = depth + 1u;
+                    (*next_stack_entry).center = cell_center;
+                    (*next_stack_entry).rotation = rotation * orientation_matrix(attachment & ATTACHMENT_ORIENTATION_MASK);
+
+                    stack_pos = stack_pos + 2u;
+                    break;
+                }
+                case 2u: {
+                // case CELL_TYPE_DATA: {
                     hit_dist = intersection.distance;
-                    color = vec4<f32>(
-                        red,
-                        green,
-                        blue,
-                        alpha
-                    );
+                    color = palette_color(cell);
 
                     continue;
                 }
@@ -276,9 +346,21 @@ fn fragment(in: FragmentInput) -> FragmentOutput {
     // let best = model_ray_origin + best_t * model_ray_dir;
 
     let model_front_face_ray_dir = normalize(best - model_ray_origin);
-    let model_front_face_pos = best / half_world_size; // [-1, 1]
-
-    let result = trace_voxel(model_front_face_ray_dir, model_front_face_pos, model_ray_origin);
+    // The octree covers a power-of-two cube which is larger than the volume itself when its sides
+    // aren't equal powers of two. Voxel (0, 0, 0) sits at the volume's -x, -y, +z corner (the
+    // cell ordering in `trace_voxel` flips z), so the cube is anchored there.
+    let octree_world_size = exp2(ceil(log2(max(max(voxel_volume.size.x, voxel_volume.size.y), voxel_volume.size.z)))) * voxel_volume.resolution.x;
+    let octree_anchor = vec3<f32>(-half_world_size.x, -half_world_size.y, half_world_size.z);
+    let model_front_face_pos = (best - octree_anchor) / octree_world_size * 2.0 + vec3<f32>(-1.0, -1.0, 1.0); // [-1, 1]
+
+    // Stop descending once a cell covers about a pixel. A cell of a grid at stack depth d spans
+    // 2^(max_depth - d) voxels; the stack starts at depth 1 for the root.
+    let max_depth = ceil(log2(max(max(voxel_volume.size.x, voxel_volume.size.y), voxel_volume.size.z)));
+    let pixel_world_size = length(best - model_ray_origin) * 2.0 / (view.projection[1][1] * view.height);
+    let voxels_per_pixel = pixel_world_size / voxel_volume.resolution.x;
+    let lod_max_depth = u32(clamp(max_depth - floor(log2(voxels_per_pixel)), 1.0, f32(MAX_STACK_DEPTH)));
+
+    let result = trace_voxel(model_front_face_ray_dir, model_front_face_pos, model_ray_origin, lod_max_depth);
 
     let distance = length(result.hit_point);
 
@@ -0,0 +1,47 @@
+use bevy::{reflect::TypeUuid, prelude::{Handle, Transform}};
+
+use crate::VoxelVolume;
+
+/// A set of [`VoxelVolume`]s placed relative to each other, as imported from files holding
+/// several models. Loaders add it as a labeled asset next to the volumes it references.
+#[derive(Debug, Clone, Default, TypeUuid)]
+#[uuid = "0b3f1f4e-6a0c-4d9e-9a55-3c2d8f6b7e21"]
+pub struct VoxelScene {
+    pub volumes: Vec<Handle<VoxelVolume>>,
+    pub roots: Vec<VoxelSceneNode>
+}
+
+/// A node of a [`VoxelScene`], placed relative to its parent.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VoxelSceneNode {
+    pub name: Option<String>,
+    pub transform: Transform,
+    /// The index in `volumes` of the volume shown at this node.
+    pub volume: Option<usize>,
+    pub children: Vec<VoxelSceneNode>
+}
+
+impl VoxelScene {
+    /// Every volume placed in the scene, with its transform relative to the scene root.
+    pub fn instances(&self) -> Vec<(Handle<VoxelVolume>, Transform)> {
+        let mut instances = Vec::new();
+        for root in &self.roots {
+            root.add_instances(&self.volumes, Transform::identity(), &mut instances);
+        }
+
+        instances
+    }
+}
+
+impl VoxelSceneNode {
+    fn add_instances(&self, volumes: &[Handle<VoxelVolume>], parent: Transform, instances: &mut Vec<(Handle<VoxelVolume>, Transform)>) {
+        let transform = parent.mul_transform(self.transform);
+        if let Some(volume) = self.volume.and_then(|volume| volumes.get(volume)) {
+            instances.push((volume.clone(), transform));
+        }
+
+        for child in &self.children {
+            child.add_instances(volumes, transform, instances);
+        }
+    }
+}
@@ -1,6 +1,8 @@
-use bevy::{reflect::TypeUuid, math::{Vec3, Mat4}, render::{render_asset::{RenderAsset, PrepareAssetError}, render_resource::{Buffer, BindGroup, BufferInitDescriptor, BufferUsages, BindGroupDescriptor, BindGroupEntry, IndexFormat, ShaderType}, renderer::RenderDevice}, ecs::system::{lifetimeless::SRes, SystemParamItem}, core::{cast_slice, bytes_of}, prelude::{HandleUntyped, Component, ResMut, Assets, Mesh, shape}};
+use std::collections::HashSet;
 
-use crate::{VoxelPipeline, Octree};
+use bevy::{reflect::TypeUuid, math::{Vec3, Mat4}, render::{render_asset::{RenderAsset, PrepareAssetError, RenderAssets}, render_resource::{Buffer, BindGroup, BufferInitDescriptor, BufferDescriptor, BufferUsages, BindGroupDescriptor, BindGroupEntry, IndexFormat, ShaderType, CommandEncoderDescriptor}, renderer::{RenderDevice, RenderQueue}, Extract}, ecs::system::{lifetimeless::SRes, SystemParamItem}, core::{cast_slice, bytes_of}, asset::AssetEvent, prelude::{HandleUntyped, Handle, Component, Commands, EventReader, Res, ResMut, Assets, Mesh, shape}};
+
+use crate::{VoxelPipeline, Octree, IndirectionGrid};
 
 pub const DEFAULT_VOXEL_VOLUME_HANDLE: HandleUntyped =
     HandleUntyped::weak_from_u64(VoxelVolume::TYPE_UUID, 12003909316817809417);
@@ -22,23 +24,42 @@ impl VoxelVolume {
 
     pub fn with_resolution(size: [u32; 3], voxels_per_meter: u32) -> Self {
         let max_size = size[0].max(size[1]).max(size[2]);
-        let resolution = 1.0f32 / (voxels_per_meter as f32);
 
+        VoxelVolume::from_octree(
+            1.0f32 / (voxels_per_meter as f32),
+            Vec3::new(size[0] as f32, size[1] as f32, size[2] as f32),
+            Octree::new(Octree::depth_for_size(max_size))
+        )
+    }
+
+    /// Wraps an existing octree in a volume of `size` voxels, each `resolution` meters wide, with
+    /// an empty palette.
+    pub fn from_octree(resolution: f32, size: Vec3, data: Octree) -> Self {
         VoxelVolume {
             resolution,
-            size: Vec3::new(size[0] as f32, size[1] as f32, size[2] as f32),
+            size,
             palette: [0;  256],
-            data: Octree::new((max_size as f32).log2() as u8),
+            data,
             mesh: Mesh::from(shape::Box::new(
-                resolution * (size[0] as f32),
-                resolution * (size[1] as f32),
-                resolution * (size[2] as f32)
+                resolution * size.x,
+                resolution * size.y,
+                resolution * size.z
             ))
         }
     }
 }
 
 impl VoxelVolume {
+    /// The offset of the palette in the output of [`VoxelVolume::to_bytes`].
+    pub const PALETTE_OFFSET: usize = 16 + 16;
+    /// The offset of the packed octree pool in the output of [`VoxelVolume::to_bytes`].
+    pub const POOL_OFFSET: usize = VoxelVolume::PALETTE_OFFSET + 1024;
+
+    /// The length of the output of [`VoxelVolume::to_bytes`].
+    pub fn byte_len(&self) -> usize {
+        VoxelVolume::POOL_OFFSET + self.data.packed_grid_count() * IndirectionGrid::BYTE_LEN
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let resolution_vec = Vec3::splat(self.resolution);
         let resolution_bytes = bytes_of(&resolution_vec);
@@ -101,9 +122,179 @@ pub struct GpuVoxelVolume {
     pub vertex_buffer: Buffer,
     /// A buffer containing the [`VoxelVolumeBufferData`] of the volume.
     pub buffer: Buffer,
+    /// The size of `buffer` in bytes, which may exceed the size of the data it holds.
+    pub buffer_capacity: u64,
     /// The bind group specifying how the [`VoxelVolumeUniformData`] and [`VoxelVolumeBufferData`] are bound.
     pub bind_group: BindGroup,
-    pub index_info: GpuBufferInfo
+    pub index_info: GpuBufferInfo,
+    /// The palette as last written to `buffer`, used to upload only the entries that change.
+    pub palette: [u32; 256]
+}
+
+impl GpuVoxelVolume {
+    /// Writes the changed ranges of `update` to the buffer, reallocating it first if the packed
+    /// pool no longer fits.
+    pub fn apply_update(
+        &mut self,
+        update: VoxelVolumeUpdate,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+        voxel_pipeline: &VoxelPipeline
+    ) {
+        if update.byte_len > self.buffer_capacity {
+            // Grow geometrically so a steady stream of edits doesn't reallocate every frame.
+            let buffer_capacity = update.byte_len.max(self.buffer_capacity * 2);
+            let buffer = render_device.create_buffer(&BufferDescriptor {
+                label: None,
+                size: buffer_capacity,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+                mapped_at_creation: false
+            });
+
+            let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+            encoder.copy_buffer_to_buffer(&self.buffer, 0, &buffer, 0, self.buffer_capacity);
+            render_queue.submit([encoder.finish()]);
+
+            self.bind_group = create_voxel_volume_bind_group(render_device, voxel_pipeline, &buffer);
+            self.buffer = buffer;
+            self.buffer_capacity = buffer_capacity;
+        }
+
+        let mut entry = 0;
+        while entry < self.palette.len() {
+            if self.palette[entry] == update.palette[entry] {
+                entry += 1;
+                continue;
+            }
+
+            let start = entry;
+            while entry < self.palette.len() && self.palette[entry] != update.palette[entry] {
+                entry += 1;
+            }
+            render_queue.write_buffer(
+                &self.buffer,
+                (VoxelVolume::PALETTE_OFFSET + start * 4) as u64,
+                cast_slice(&update.palette[start..entry])
+            );
+        }
+        self.palette = update.palette;
+
+        for (offset, bytes) in update.grids {
+            render_queue.write_buffer(&self.buffer, (VoxelVolume::POOL_OFFSET + offset) as u64, &bytes);
+        }
+    }
+}
+
+/// The parts of a [`VoxelVolume`] that changed since it was last extracted.
+pub struct VoxelVolumeUpdate {
+    /// The length the buffer needs to hold the whole volume.
+    pub byte_len: u64,
+    pub palette: [u32; 256],
+    /// Runs of packed grids, as returned by [`Octree::changed_bytes`].
+    pub grids: Vec<(usize, Vec<u8>)>
+}
+
+pub enum ExtractedVoxelVolume {
+    /// A volume that has to be uploaded from scratch.
+    Full(VoxelVolume),
+    Update(VoxelVolumeUpdate)
+}
+
+/// Voxel volume assets extracted this frame, prepared by [`prepare_voxel_volume_assets`].
+#[derive(Default)]
+pub struct ExtractedVoxelVolumes {
+    extracted: Vec<(Handle<VoxelVolume>, ExtractedVoxelVolume)>,
+    removed: Vec<Handle<VoxelVolume>>
+}
+
+/// Extracts created and modified [`VoxelVolume`]s. Volumes that already have a [`GpuVoxelVolume`]
+/// only send the grids changed since the last extract; new volumes, and volumes whose packed
+/// layout moved, are cloned whole, sharing the octree's grids with the main world rather than
+/// copying them. Changes to `size`, `resolution` or `mesh` are not tracked, so
+/// a volume whose shape changes should be added as a new asset.
+pub fn extract_voxel_volume_assets(
+    mut commands: Commands,
+    mut events: Extract<EventReader<AssetEvent<VoxelVolume>>>,
+    assets: Extract<Res<Assets<VoxelVolume>>>,
+    render_voxel_volumes: Res<RenderAssets<VoxelVolume>>
+) {
+    let mut changed_assets = HashSet::default();
+    let mut removed = Vec::new();
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                changed_assets.insert(handle.clone_weak());
+            }
+            AssetEvent::Removed { handle } => {
+                changed_assets.remove(handle);
+                removed.push(handle.clone_weak());
+            }
+        }
+    }
+
+    let mut extracted = Vec::with_capacity(changed_assets.len());
+    for handle in changed_assets.drain() {
+        if let Some(voxel_volume) = assets.get(&handle) {
+            let changes = voxel_volume.data.take_changes();
+            let extracted_asset = if changes.relayout || !render_voxel_volumes.contains_key(&handle) {
+                ExtractedVoxelVolume::Full(voxel_volume.extract_asset())
+            } else {
+                ExtractedVoxelVolume::Update(VoxelVolumeUpdate {
+                    byte_len: voxel_volume.byte_len() as u64,
+                    palette: voxel_volume.palette,
+                    grids: voxel_volume.data.changed_bytes(&changes)
+                })
+            };
+            extracted.push((handle, extracted_asset));
+        }
+    }
+
+    commands.insert_resource(ExtractedVoxelVolumes {
+        extracted,
+        removed
+    });
+}
+
+/// Uploads the volumes extracted by [`extract_voxel_volume_assets`].
+pub fn prepare_voxel_volume_assets(
+    mut extracted_assets: ResMut<ExtractedVoxelVolumes>,
+    mut render_voxel_volumes: ResMut<RenderAssets<VoxelVolume>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    voxel_pipeline: Res<VoxelPipeline>
+) {
+    for removed in std::mem::take(&mut extracted_assets.removed) {
+        render_voxel_volumes.remove(&removed);
+    }
+
+    let mut param = (render_device, voxel_pipeline);
+    for (handle, extracted_asset) in std::mem::take(&mut extracted_assets.extracted) {
+        match extracted_asset {
+            ExtractedVoxelVolume::Full(voxel_volume) => {
+                if let Ok(prepared_asset) = VoxelVolume::prepare_asset(voxel_volume, &mut param) {
+                    render_voxel_volumes.insert(handle, prepared_asset);
+                }
+            },
+            ExtractedVoxelVolume::Update(update) => {
+                if let Some(gpu_voxel_volume) = render_voxel_volumes.get_mut(&handle) {
+                    gpu_voxel_volume.apply_update(update, &param.0, &render_queue, &param.1);
+                }
+            }
+        }
+    }
+}
+
+fn create_voxel_volume_bind_group(render_device: &RenderDevice, voxel_pipeline: &VoxelPipeline, buffer: &Buffer) -> BindGroup {
+    render_device.create_bind_group(&BindGroupDescriptor {
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }
+        ],
+        label: None,
+        layout: &voxel_pipeline.voxel_layout,
+    })
 }
 
 impl RenderAsset for VoxelVolume {
@@ -127,10 +318,11 @@ impl RenderAsset for VoxelVolume {
             contents: &vertex_buffer_data
         });
 
+        let contents = voxel_volume.to_bytes();
         let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            contents: voxel_volume.to_bytes().as_slice(),
+            contents: contents.as_slice(),
             label: None,
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
         });
 
         let index_info = mesh.get_index_buffer_bytes().map_or(
@@ -147,22 +339,15 @@ impl RenderAsset for VoxelVolume {
             index_format: mesh.indices().unwrap().into(),
         });
 
-        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: buffer.as_entire_binding(),
-                }
-            ],
-            label: None,
-            layout: &voxel_pipeline.voxel_layout,
-        });
+        let bind_group = create_voxel_volume_bind_group(render_device, voxel_pipeline, &buffer);
 
         Ok(GpuVoxelVolume {
             vertex_buffer,
             buffer,
+            buffer_capacity: contents.len() as u64,
             bind_group,
-            index_info
+            index_info,
+            palette: voxel_volume.palette
         })
     }
 }
\ No newline at end of file
@@ -1,5 +1,9 @@
 mod octree;
+mod orientation;
+mod payload;
 
 pub use self::{
-    octree::*
+    octree::*,
+    orientation::*,
+    payload::*
 };
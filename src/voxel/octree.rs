@@ -1,81 +1,370 @@
-use std::{cell::RefCell, collections::VecDeque, rc::Rc};
-use serde::Serialize;
+use std::{cell::RefCell, collections::{BTreeSet, VecDeque}, rc::Rc, sync::Mutex};
+use serde::{Serialize, Deserialize};
 
-use crate::{u24_to_bytes, bytes_to_u24};
+use crate::{Orientation, VoxelPayload, PackedPayload, MAX_PACKED_PAYLOAD};
+
+mod csg;
+mod dag;
+mod dense;
+mod encoding;
+mod neighbors;
+mod patch;
+mod pool;
+mod raycast;
+mod shapes;
+mod snapshot;
+mod stats;
+mod transform;
+
+pub use self::{
+    neighbors::*,
+    patch::*,
+    raycast::*,
+    snapshot::*,
+    stats::*
+};
+
+use self::pool::GridPool;
+
+/// The largest pool index a [`GridCell::GridPointer`] can hold once packed by [`GridCell::to_u32`].
+pub const MAX_POOL_INDEX: u32 = (1 << 30) - 1;
 
 /// https://developer.nvidia.com/gpugems/gpugems2/part-v-image-oriented-computing/chapter-37-octree-textures-gpu
-#[derive(Clone, Debug, Serialize)]
-pub struct Octree {
-    #[serde(skip)]
+///
+/// Material cells hold a [`VoxelPayload`], by default the material color read by the renderer.
+/// Clones share their grids until either side writes to them, see [`Octree::snapshot`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound = "T: VoxelPayload")]
+pub struct Octree<T = [u8; 3]> {
     depth_max: u8,
-    #[serde(skip)]
     free_indices: VecDeque<u32>,
-    indirection_pool: Vec<IndirectionGrid>,
+    indirection_pool: GridPool<T>,
+    /// Octrees referenced by [`GridCell::Attachment`] cells, by index.
+    attachments: Vec<Octree<T>>,
+    /// Grids written since the last call to [`Octree::take_changes`].
+    #[serde(skip)]
+    changes: ChangeTracker,
+    /// Set when grids may be shared between several cells, see [`Octree::to_dag`].
+    #[serde(default)]
+    dag: bool,
     // released_grids: VecDeque<?>
 }
 
 impl Octree {
-    pub fn new(depth_max: u8) -> Octree {
-        let mut pool = Vec::with_capacity(1);
+    /// Returns the depth needed for an octree to hold `size` voxels along its longest side,
+    /// rounding up for sizes that aren't a power of two. Never less than 1, as an octree of depth 0
+    /// holds no voxels at all.
+    pub fn depth_for_size(size: u32) -> u8 {
+        size.next_power_of_two().trailing_zeros().max(1) as u8
+    }
+
+    /// The minimum corner of cell `cell_index` of a grid whose minimum corner is `grid_min` and
+    /// whose cells are `cell_size` voxels wide. The inverse of [`Octree::cell_index`].
+    pub(crate) fn cell_min(grid_min: [u32; 3], cell_index: u32, cell_size: u32) -> [u32; 3] {
+        [0, 1, 2].map(|i| grid_min[i] + ((cell_index >> i) & 1) * cell_size)
+    }
+}
+
+impl<T: VoxelPayload> Octree<T> {
+    pub fn new(depth_max: u8) -> Octree<T> {
+        if depth_max > 31 {
+            panic!("octree depth {} exceeds the maximum of 31", depth_max);
+        }
+
+        let mut pool = GridPool::new();
         pool.push(IndirectionGrid::default());
         Octree {
             depth_max,
             indirection_pool: pool,
             free_indices: VecDeque::with_capacity(1),
+            attachments: Vec::new(),
+            changes: ChangeTracker::default(),
+            dag: false,
         }
     }
 
-    pub fn add_data(&mut self, mut x: u8, mut y: u8, mut z: u8, data: [u8; 3]) {
+    pub fn depth_max(&self) -> u8 {
+        self.depth_max
+    }
+
+    /// The number of voxels along each side of the cube covered by the octree.
+    pub fn size(&self) -> u32 {
+        1 << self.depth_max
+    }
+
+    /// Writes material data at the given coordinates. Collapsed regions containing the voxel are
+    /// split back out, and grids left holding eight identical materials are folded into their
+    /// parent cell. Voxels covered by an attachment are left untouched.
+    pub fn add_data(&mut self, x: u32, y: u32, z: u32, data: T) {
+        if !self.in_bounds(x, y, z) {
+            return;
+        }
+
+        self.unshare();
+
+        let mut path: Vec<(u32, u32)> = Vec::with_capacity(self.depth_max as usize);
         let mut pool_index = 0u32;
 
         let mut depth = 0u8;
         while depth < self.depth_max {
             let grid = &self.indirection_pool[pool_index as usize];
-            let grid_cell_size = 2u16.pow(u32::from(self.depth_max - grid.depth)) / 2;
-            let grid_x = (x as u16 / grid_cell_size) as u8;
-            let grid_y = (y as u16 / grid_cell_size) as u8;
-            let grid_z = (z as u16 / grid_cell_size) as u8;
+            let cell_index = Self::cell_index(x, y, z, self.cell_size(grid.depth));
+            let cell = grid.cells[cell_index as usize];
+            path.push((pool_index, cell_index));
 
-            let cell_index = u32::from(grid_x + grid_y * 2 + grid_z * 2 * 2);
-
-            let mut cell = grid.cells[cell_index as usize];
-            
-            match cell.cell_type {
-                GridCellType::Empty => {
+            match cell {
+                GridCell::Empty => {
                     if depth == self.depth_max - 1 {
-                        cell.cell_type = GridCellType::Material;
-                        cell.data = data;
-                        self.update_grid_cell(pool_index, cell_index, cell);
+                        self.update_grid_cell(pool_index, cell_index, GridCell::Material(data));
+                        break;
+                    } else {
+                        pool_index = self.create_grid_child(pool_index, cell_index);
+                    }
+                },
+                GridCell::GridPointer(child_pool_index) => {
+                    pool_index = child_pool_index;
+                },
+                GridCell::Material(cell_data) => {
+                    if cell_data == data {
                         return;
+                    }
+
+                    if depth == self.depth_max - 1 {
+                        self.update_grid_cell(pool_index, cell_index, GridCell::Material(data));
+                        break;
                     } else {
-                        let child_pool_index = self.create_grid_child(pool_index, grid_x, grid_y, grid_z);
-                        pool_index = child_pool_index;
+                        pool_index = self.split_grid_cell(pool_index, cell_index);
                     }
                 },
-                GridCellType::GridPointer => {
-                    pool_index = bytes_to_u24(cell.data);
+                _ => return
+            }
+
+            depth += 1;
+        }
+
+        self.collapse_path(path);
+    }
+
+    /// Clears the voxel at the given coordinates. Grids left fully empty are unlinked from their
+    /// parent and their pool index is recycled through `free_indices`.
+    /// Returns `true` if a voxel was removed.
+    pub fn remove_data(&mut self, x: u32, y: u32, z: u32) -> bool {
+        if !self.in_bounds(x, y, z) {
+            return false;
+        }
+
+        self.unshare();
+
+        let mut path: Vec<(u32, u32)> = Vec::with_capacity(self.depth_max as usize);
+        let mut pool_index = 0u32;
+
+        let mut depth = 0u8;
+        while depth < self.depth_max {
+            let grid = &self.indirection_pool[pool_index as usize];
+            let cell_index = Self::cell_index(x, y, z, self.cell_size(grid.depth));
+            let cell = grid.cells[cell_index as usize];
+            path.push((pool_index, cell_index));
+
+            match cell {
+                GridCell::GridPointer(child_pool_index) => {
+                    pool_index = child_pool_index;
                 },
-                GridCellType::Material => {
-                    cell.data = data;
-                    self.update_grid_cell(pool_index, cell_index, cell);
-                    return;
+                GridCell::Material(_) => {
+                    if depth == self.depth_max - 1 {
+                        self.update_grid_cell(pool_index, cell_index, GridCell::Empty);
+                        break;
+                    } else {
+                        pool_index = self.split_grid_cell(pool_index, cell_index);
+                    }
                 },
-                _ => {}
+                _ => return false
             }
-            
-            // pool_index = u32::from(u16::from(pool_offsets[0]) + (u16::from(pool_offsets[1]) * 256u16) + (u16::from(pool_offsets[2]) * 256 * 256));
-            x -= (grid_x as u16 * grid_cell_size) as u8;
-            y -= (grid_y as u16 * grid_cell_size) as u8;
-            z -= (grid_z as u16 * grid_cell_size) as u8;
+
             depth += 1;
         }
+
+        self.collapse_path(path);
+
+        true
     }
 
-    fn root(&mut self) -> &mut IndirectionGrid {
+    /// Returns the data stored at the given coordinates, or `None` if the cell is empty.
+    pub fn get(&self, x: u32, y: u32, z: u32) -> Option<VoxelData<T>> {
+        self.get_at_depth(x, y, z, self.depth_max)
+    }
+
+    pub fn contains(&self, x: u32, y: u32, z: u32) -> bool {
+        self.get(x, y, z).is_some()
+    }
+
+    /// Descends towards the given coordinates, stopping at grids deeper than `depth`, and returns
+    /// the first non-pointer cell found. Returns `None` for empty cells, and for cells that are
+    /// still grid pointers once `depth` is reached.
+    pub fn get_at_depth(&self, x: u32, y: u32, z: u32, depth: u8) -> Option<VoxelData<T>> {
+        if !self.in_bounds(x, y, z) {
+            return None;
+        }
+
+        let mut pool_index = 0u32;
+
+        let mut grid_depth = 0u8;
+        while grid_depth < self.depth_max && grid_depth <= depth {
+            let grid = &self.indirection_pool[pool_index as usize];
+            let cell = grid.cells[Self::cell_index(x, y, z, self.cell_size(grid.depth)) as usize];
+
+            match cell {
+                GridCell::Empty => return None,
+                GridCell::GridPointer(child_pool_index) => {
+                    pool_index = child_pool_index;
+                },
+                GridCell::Material(data) => return Some(VoxelData::Material(data)),
+                GridCell::Attachment(attachment, orientation) => return Some(VoxelData::Attachment(attachment, orientation))
+            }
+
+            grid_depth += 1;
+        }
+
+        None
+    }
+
+    /// Iterates over every voxel holding material data, yielding `(x, y, z, data)`.
+    pub fn iter(&self) -> OctreeIter<'_, T> {
+        self.iter_in_box([0, 0, 0], [u32::MAX, u32::MAX, u32::MAX])
+    }
+
+    /// Iterates over the material voxels inside the inclusive box `min..=max`.
+    pub fn iter_in_box(&self, min: [u32; 3], max: [u32; 3]) -> OctreeIter<'_, T> {
+        let mut stack = Vec::with_capacity(self.depth_max as usize);
+        if self.depth_max > 0 {
+            stack.push(OctreeIterFrame {
+                pool_index: 0,
+                cell_index: 0,
+                origin: [0, 0, 0]
+            });
+        }
+
+        OctreeIter {
+            octree: self,
+            min,
+            max,
+            stack,
+            region: None
+        }
+    }
+
+    /// Adds an octree that can then be placed any number of times with [`Octree::attach`],
+    /// returning its attachment index. Attached octrees share the palette of the host volume.
+    pub fn add_attachment(&mut self, octree: Octree<T>) -> u32 {
+        self.attachments.push(octree);
+        self.changes.get_mut().relayout = true;
+        (self.attachments.len() - 1) as u32
+    }
+
+    pub fn attachment(&self, attachment: u32) -> Option<&Octree<T>> {
+        self.attachments.get(attachment as usize)
+    }
+
+    pub fn attachments(&self) -> &[Octree<T>] {
+        &self.attachments
+    }
+
+    /// Places an attachment in the cell at grid depth `depth` containing the given coordinates,
+    /// replacing whatever the cell held. The attached octree is scaled to fill the cell and rotated
+    /// by `orientation` about its center.
+    pub fn attach(&mut self, x: u32, y: u32, z: u32, depth: u8, attachment: u32, orientation: Orientation) {
+        if (attachment as usize) >= self.attachments.len() {
+            panic!("attachment {} does not exist!", attachment);
+        }
+
+        self.set_cell(x, y, z, depth, GridCell::Attachment(attachment, orientation));
+    }
+
+    /// Clears the attachment cell covering the given coordinates, returning what it held.
+    pub fn detach(&mut self, x: u32, y: u32, z: u32) -> Option<(u32, Orientation)> {
+        if !self.in_bounds(x, y, z) {
+            return None;
+        }
+
+        let mut pool_index = 0u32;
+        for depth in 0..self.depth_max {
+            let grid = &self.indirection_pool[pool_index as usize];
+            match grid.cells[Self::cell_index(x, y, z, self.cell_size(grid.depth)) as usize] {
+                GridCell::GridPointer(child_pool_index) => pool_index = child_pool_index,
+                GridCell::Attachment(attachment, orientation) => {
+                    self.set_cell(x, y, z, depth, GridCell::Empty);
+                    return Some((attachment, orientation));
+                },
+                _ => return None
+            }
+        }
+
+        None
+    }
+
+    /// Replaces the cell at grid depth `depth` containing the given coordinates, releasing the
+    /// subtree it pointed to. Collapsed cells above it are split; attachments are never split, so
+    /// nothing is written below one. Returns `false` if the cell couldn't be reached.
+    fn set_cell(&mut self, x: u32, y: u32, z: u32, depth: u8, cell: GridCell<T>) -> bool {
+        if !self.in_bounds(x, y, z) || depth >= self.depth_max {
+            return false;
+        }
+
+        self.unshare();
+
+        let mut path: Vec<(u32, u32)> = Vec::with_capacity(depth as usize + 1);
+        let mut pool_index = 0u32;
+
+        for grid_depth in 0..=depth {
+            let grid = &self.indirection_pool[pool_index as usize];
+            let cell_index = Self::cell_index(x, y, z, self.cell_size(grid.depth));
+            let current = grid.cells[cell_index as usize];
+            path.push((pool_index, cell_index));
+
+            if grid_depth == depth {
+                if current == cell {
+                    return true;
+                }
+
+                if let GridCell::GridPointer(child_pool_index) = current {
+                    self.release_subtree(child_pool_index);
+                }
+                self.update_grid_cell(pool_index, cell_index, cell);
+                break;
+            }
+
+            pool_index = match current {
+                GridCell::Empty => self.create_grid_child(pool_index, cell_index),
+                GridCell::GridPointer(child_pool_index) => child_pool_index,
+                GridCell::Material(_) => self.split_grid_cell(pool_index, cell_index),
+                GridCell::Attachment(..) => return false
+            };
+        }
+
+        self.collapse_path(path);
+
+        true
+    }
+
+    fn in_bounds(&self, x: u32, y: u32, z: u32) -> bool {
+        self.depth_max > 0 && (x | y | z) >> self.depth_max == 0
+    }
+
+    /// The side length, in voxels, of the cells of a grid at `grid_depth`.
+    fn cell_size(&self, grid_depth: u8) -> u32 {
+        1 << (self.depth_max - grid_depth - 1)
+    }
+
+    /// The index of the cell containing the given coordinates within a grid whose cells are
+    /// `cell_size` voxels wide.
+    fn cell_index(x: u32, y: u32, z: u32, cell_size: u32) -> u32 {
+        u32::from(x & cell_size != 0) + u32::from(y & cell_size != 0) * 2 + u32::from(z & cell_size != 0) * 2 * 2
+    }
+
+    fn root(&mut self) -> &mut IndirectionGrid<T> {
         &mut self.indirection_pool[0]
     }
 
-    fn create_grid_child(&mut self, pool_index: u32, grid_x: u8, grid_y: u8, grid_z: u8) -> u32 {
+    fn create_grid_child(&mut self, pool_index: u32, cell_index: u32) -> u32 {
         let grid = &self.indirection_pool[pool_index as usize].clone();
 
         let depth = grid.depth + 1;
@@ -84,20 +373,11 @@ impl Octree {
             panic!("max tree depth exceeded!");
         }
 
-        let child_pool_index = self.free_indices.pop_front().unwrap_or_else(|| {
-           self.indirection_pool.reserve(1);
-           self.indirection_pool.len() as u32
-        });
-
-        let child_grid = IndirectionGrid::new(depth);
-        self.indirection_pool.insert(child_pool_index as usize, child_grid);
+        let child_pool_index = self.alloc_grid(IndirectionGrid::new(depth));
 
         let mut grid_cells = grid.cells;
 
-        grid_cells[usize::from(grid_x + grid_y * 2 + grid_z * 2 * 2)] = GridCell::new(
-            GridCellType::GridPointer,
-            u24_to_bytes(child_pool_index)
-        );
+        grid_cells[cell_index as usize] = GridCell::GridPointer(child_pool_index);
 
         self.indirection_pool[pool_index as usize].cells = grid_cells;
 
@@ -108,42 +388,435 @@ impl Octree {
         //     cells: grid_cells
         // };
 
+        self.changes.get_mut().grids.insert(pool_index);
+
+        child_pool_index
+    }
+
+    /// Stores a grid in the pool, reusing a released index if there is one, and returns its index.
+    fn alloc_grid(&mut self, grid: IndirectionGrid<T>) -> u32 {
+        let pool_index = self.free_indices.pop_front().unwrap_or(self.indirection_pool.len() as u32);
+
+        if pool_index > MAX_POOL_INDEX {
+            panic!("indirection pool exhausted!");
+        }
+
+        if (pool_index as usize) < self.indirection_pool.len() {
+            self.indirection_pool[pool_index as usize] = grid;
+        } else {
+            self.indirection_pool.push(grid);
+            // Growing the pool moves the attached pools packed after it.
+            if !self.attachments.is_empty() {
+                self.changes.get_mut().relayout = true;
+            }
+        }
+
+        self.changes.get_mut().grids.insert(pool_index);
+        pool_index
+    }
+
+    /// Replaces a collapsed cell with a child grid holding eight copies of it, returning the
+    /// child's pool index.
+    fn split_grid_cell(&mut self, pool_index: u32, cell_index: u32) -> u32 {
+        let cell = self.indirection_pool[pool_index as usize].cells[cell_index as usize];
+        let child_pool_index = self.create_grid_child(pool_index, cell_index);
+        self.indirection_pool[child_pool_index as usize].cells = [cell; 8];
+        self.update_lod(child_pool_index);
+
         child_pool_index
-        // TODO: set update
     }
 
-    fn update_grid_cell(&mut self, pool_index: u32, cell_index: u32, cell: GridCell) {
+    /// Walks back up a descent path, folding every grid whose cells are all empty or all the
+    /// same material into its parent cell, and refreshing the LOD cells of the grids that remain.
+    fn collapse_path(&mut self, mut path: Vec<(u32, u32)>) {
+        while let Some((pool_index, _)) = path.pop() {
+            if pool_index != 0 {
+                if let Some(cell) = self.indirection_pool[pool_index as usize].uniform_cell() {
+                    if let Some(&(parent_pool_index, parent_cell_index)) = path.last() {
+                        self.update_grid_cell(parent_pool_index, parent_cell_index, cell);
+                        self.release_grid(pool_index);
+                        continue;
+                    }
+                }
+            }
+
+            self.update_lod(pool_index);
+        }
+    }
+
+    /// Recomputes the LOD cell of a grid from its cells and the LOD cells of its children, which
+    /// must already be up to date.
+    fn update_lod(&mut self, pool_index: u32) {
+        let mut materials: Vec<(T, u32)> = Vec::with_capacity(8);
+        for cell in &self.indirection_pool[pool_index as usize].cells {
+            let material = match *cell {
+                GridCell::Material(data) => data,
+                GridCell::GridPointer(child_pool_index) => match self.indirection_pool[child_pool_index as usize].lod {
+                    GridCell::Material(data) => data,
+                    _ => continue
+                },
+                GridCell::Attachment(attachment, _) => match self.attachments[attachment as usize].indirection_pool[0].lod {
+                    GridCell::Material(data) => data,
+                    _ => continue
+                },
+                GridCell::Empty => continue
+            };
+
+            match materials.iter_mut().find(|(data, _)| *data == material) {
+                Some((_, count)) => *count += 1,
+                None => materials.push((material, 1))
+            }
+        }
+
+        // Ties go to the material seen first, so the result only depends on the cells.
+        let mut lod = GridCell::Empty;
+        let mut best = 0;
+        for (data, count) in materials {
+            if count > best {
+                lod = GridCell::Material(data);
+                best = count;
+            }
+        }
+
+        let grid = &mut self.indirection_pool[pool_index as usize];
+        if grid.lod != lod {
+            grid.lod = lod;
+            self.changes.get_mut().grids.insert(pool_index);
+        }
+    }
+
+    /// Releases a grid and every grid below it.
+    fn release_subtree(&mut self, pool_index: u32) {
+        let cells = self.indirection_pool[pool_index as usize].cells;
+        for cell in cells {
+            if let GridCell::GridPointer(child_pool_index) = cell {
+                self.release_subtree(child_pool_index);
+            }
+        }
+
+        self.release_grid(pool_index);
+    }
+
+    /// Returns a grid to the pool so `create_grid_child` can reuse its index.
+    fn release_grid(&mut self, pool_index: u32) {
+        self.indirection_pool[pool_index as usize] = IndirectionGrid::default();
+        self.free_indices.push_back(pool_index);
+        self.changes.get_mut().grids.insert(pool_index);
+    }
+
+    fn update_grid_cell(&mut self, pool_index: u32, cell_index: u32, cell: GridCell<T>) {
         let grid = &mut self.indirection_pool[pool_index as usize];
         grid.cells[cell_index as usize] = cell;
+        self.changes.get_mut().grids.insert(pool_index);
+    }
+
+    /// Returns the grids written since the last call, and starts recording afresh. Takes `&self`
+    /// so the render world can drain the changes of an asset without flagging it as modified.
+    pub fn take_changes(&self) -> OctreeChanges {
+        std::mem::take(&mut *self.changes.0.lock().unwrap())
     }
 
+    /// The number of grids written by [`Octree::to_bytes`], including attached octrees.
+    pub fn packed_grid_count(&self) -> usize {
+        self.indirection_pool.len() + self.attachments.iter().map(Self::packed_grid_count).sum::<usize>()
+    }
+}
+
+impl<T: PackedPayload> Octree<T> {
+    /// Packs the grids listed in `changes` as [`Octree::to_bytes`] would, merging consecutive pool
+    /// indices into a single run. Returns `(byte offset, bytes)` pairs relative to the start of
+    /// the packed pool. Only meaningful when `changes.relayout` is not set.
+    pub fn changed_bytes(&self, changes: &OctreeChanges) -> Vec<(usize, Vec<u8>)> {
+        let attachment_roots = self.attachment_roots(0);
+
+        let mut runs: Vec<(usize, Vec<u8>)> = Vec::new();
+        let mut next_pool_index = None;
+        for &pool_index in &changes.grids {
+            if pool_index as usize >= self.indirection_pool.len() {
+                continue;
+            }
+
+            if next_pool_index != Some(pool_index) {
+                runs.push((pool_index as usize * IndirectionGrid::BYTE_LEN, Vec::new()));
+            }
+
+            let (_, bytes) = runs.last_mut().unwrap();
+            self.write_grid_bytes(&self.indirection_pool[pool_index as usize], 0, &attachment_roots, bytes);
+            next_pool_index = Some(pool_index + 1);
+        }
+
+        runs
+    }
+
+    /// Packs the indirection pool for the GPU. The pools of attached octrees are appended after
+    /// this one's with their pointers rebased, and attachment cells hold the pool index of the
+    /// attached root in place of the attachment index.
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-        for grid in &self.indirection_pool {
+        let mut bytes = Vec::with_capacity(self.packed_grid_count() * IndirectionGrid::BYTE_LEN);
+        self.write_bytes(&mut bytes);
+
+        bytes
+    }
+
+    fn write_bytes(&self, bytes: &mut Vec<u8>) {
+        let base = (bytes.len() / IndirectionGrid::BYTE_LEN) as u32;
+        let attachment_roots = self.attachment_roots(base);
+
+        for grid in self.indirection_pool.iter() {
+            self.write_grid_bytes(grid, base, &attachment_roots, bytes);
+        }
+
+        for attachment in &self.attachments {
+            attachment.write_bytes(bytes);
+        }
+    }
+
+    /// The packed pool index of each attachment's root when this octree's pool starts at `base`.
+    fn attachment_roots(&self, base: u32) -> Vec<u32> {
+        let mut attachment_roots = Vec::with_capacity(self.attachments.len());
+        let mut attachment_root = base + self.indirection_pool.len() as u32;
+        for attachment in &self.attachments {
+            attachment_roots.push(attachment_root);
+            attachment_root += attachment.packed_grid_count() as u32;
+        }
+
+        attachment_roots
+    }
+
+    fn write_grid_bytes(&self, grid: &IndirectionGrid<T>, base: u32, attachment_roots: &[u32], bytes: &mut Vec<u8>) {
+        for cell in grid.cells.iter().chain([&grid.lod]) {
+            let packed = match *cell {
+                GridCell::GridPointer(pool_index) => GridCell::<T>::GridPointer(base + pool_index).to_u32(),
+                GridCell::Attachment(attachment, orientation) => {
+                    let root = attachment_roots[attachment as usize];
+                    (((root << 5) | u32::from(orientation.index())) << 2) | GridCellType::Attachment as u32
+                },
+                _ => cell.to_u32()
+            };
+            bytes.extend_from_slice(&packed.to_le_bytes());
+        }
+    }
+
+    /// Rebuilds an octree from the output of [`Octree::to_bytes`]. Grid depths are recovered by
+    /// walking the pointers from the root, and grids that are not reachable from it are placed on
+    /// the free list. Grids reached more than once, at the same depth, make the result a DAG.
+    /// Returns `None` if the bytes do not describe a valid octree, or if they contain attachments,
+    /// whose octree depths are not recorded in the packed form.
+    pub fn from_bytes(depth_max: u8, bytes: &[u8]) -> Option<Octree<T>> {
+        if depth_max > 31 || bytes.is_empty() || bytes.len() % IndirectionGrid::BYTE_LEN != 0 {
+            return None;
+        }
+
+        let mut indirection_pool = Vec::with_capacity(bytes.len() / IndirectionGrid::BYTE_LEN);
+        for grid_bytes in bytes.chunks_exact(IndirectionGrid::BYTE_LEN) {
+            let mut grid = IndirectionGrid::default();
+            for (cell, cell_bytes) in grid.cells.iter_mut().chain([&mut grid.lod]).zip(grid_bytes.chunks_exact(4)) {
+                *cell = GridCell::from_u32(u32::from_le_bytes(cell_bytes.try_into().ok()?))?;
+            }
+            if !matches!(grid.lod, GridCell::Empty | GridCell::Material(_)) {
+                return None;
+            }
+            indirection_pool.push(grid);
+        }
+
+        let mut reachable = vec![false; indirection_pool.len()];
+        let mut pending = VecDeque::from([0u32]);
+        reachable[0] = true;
+        let mut dag = false;
+
+        while let Some(pool_index) = pending.pop_front() {
+            let grid = indirection_pool[pool_index as usize].clone();
             for cell in &grid.cells {
-                unsafe {
-                    let cell_bytes = ::std::slice::from_raw_parts(
-                        (cell as *const GridCell) as *const u8,
-                        ::std::mem::size_of::<GridCell>(),
-                    );
-                    bytes.extend_from_slice(cell_bytes);
+                let child_pool_index = match cell {
+                    GridCell::GridPointer(child_pool_index) => *child_pool_index,
+                    GridCell::Attachment(..) => return None,
+                    _ => continue
+                };
+
+                if grid.depth + 1 >= depth_max {
+                    return None;
                 }
+
+                match reachable.get(child_pool_index as usize) {
+                    Some(false) => {},
+                    // The root can't be shared, and neither can grids at different depths.
+                    Some(true) if child_pool_index != 0 && indirection_pool[child_pool_index as usize].depth == grid.depth + 1 => {
+                        dag = true;
+                        continue;
+                    },
+                    _ => return None
+                }
+
+                reachable[child_pool_index as usize] = true;
+                indirection_pool[child_pool_index as usize].depth = grid.depth + 1;
+                pending.push_back(child_pool_index);
             }
         }
 
-        bytes
+        let free_indices = reachable.iter()
+            .enumerate()
+            .filter(|(_, reachable)| !**reachable)
+            .map(|(pool_index, _)| pool_index as u32)
+            .collect();
+
+        Some(Octree {
+            depth_max,
+            free_indices,
+            indirection_pool: indirection_pool.into(),
+            attachments: Vec::new(),
+            changes: ChangeTracker::default(),
+            dag
+        })
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
-pub struct IndirectionGrid {
-    #[serde(skip)]
+/// The grids of an [`Octree`] written since its changes were last taken.
+#[derive(Clone, Debug, Default)]
+pub struct OctreeChanges {
+    /// Pool indices of the grids whose cells changed.
+    pub grids: BTreeSet<u32>,
+    /// Set when the packed layout moved, either because an attachment was added or because the
+    /// pool grew while attachments are packed after it. The whole buffer has to be rewritten.
+    pub relayout: bool
+}
+
+impl OctreeChanges {
+    pub fn is_empty(&self) -> bool {
+        self.grids.is_empty() && !self.relayout
+    }
+}
+
+/// Holds [`OctreeChanges`] behind a lock so they can be drained from a shared reference.
+#[derive(Debug)]
+struct ChangeTracker(Mutex<OctreeChanges>);
+
+impl ChangeTracker {
+    fn get_mut(&mut self) -> &mut OctreeChanges {
+        self.0.get_mut().unwrap()
+    }
+}
+
+/// A new octree, including a clone or a deserialized one, has never been uploaded, so it starts
+/// out asking for the whole buffer to be written. This keeps replacing an asset's octree outright
+/// from being mistaken for a handful of edits.
+impl Default for ChangeTracker {
+    fn default() -> ChangeTracker {
+        ChangeTracker(Mutex::new(OctreeChanges {
+            grids: BTreeSet::new(),
+            relayout: true
+        }))
+    }
+}
+
+impl Clone for ChangeTracker {
+    fn clone(&self) -> ChangeTracker {
+        ChangeTracker::default()
+    }
+}
+
+struct OctreeIterFrame {
+    pool_index: u32,
+    cell_index: u8,
+    origin: [u32; 3]
+}
+
+/// The part of a material cell (possibly spanning several voxels) left to be yielded.
+struct OctreeIterRegion<T> {
+    min: [u32; 3],
+    max: [u32; 3],
+    next: [u32; 3],
+    data: T
+}
+
+/// Iterator over the material voxels of an [`Octree`], created by [`Octree::iter`] and
+/// [`Octree::iter_in_box`].
+pub struct OctreeIter<'a, T = [u8; 3]> {
+    octree: &'a Octree<T>,
+    min: [u32; 3],
+    max: [u32; 3],
+    stack: Vec<OctreeIterFrame>,
+    region: Option<OctreeIterRegion<T>>
+}
+
+impl<'a, T: VoxelPayload> Iterator for OctreeIter<'a, T> {
+    type Item = (u32, u32, u32, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(region) = &mut self.region {
+                if region.next[2] <= region.max[2] {
+                    let [x, y, z] = region.next;
+                    let data = region.data;
+
+                    region.next[0] += 1;
+                    if region.next[0] > region.max[0] {
+                        region.next[0] = region.min[0];
+                        region.next[1] += 1;
+                        if region.next[1] > region.max[1] {
+                            region.next[1] = region.min[1];
+                            region.next[2] += 1;
+                        }
+                    }
+
+                    return Some((x, y, z, data));
+                }
+                self.region = None;
+            }
+
+            let frame = self.stack.last_mut()?;
+            if frame.cell_index == 8 {
+                self.stack.pop();
+                continue;
+            }
+
+            let cell_index = frame.cell_index;
+            frame.cell_index += 1;
+
+            let grid = &self.octree.indirection_pool[frame.pool_index as usize];
+            let grid_cell_size = self.octree.cell_size(grid.depth);
+            let cell_min = Octree::cell_min(frame.origin, u32::from(cell_index), grid_cell_size);
+            let cell_max = cell_min.map(|v| v + grid_cell_size - 1);
+
+            if (0..3).any(|i| cell_max[i] < self.min[i] || cell_min[i] > self.max[i]) {
+                continue;
+            }
+
+            match grid.cells[cell_index as usize] {
+                GridCell::GridPointer(child_pool_index) => {
+                    self.stack.push(OctreeIterFrame {
+                        pool_index: child_pool_index,
+                        cell_index: 0,
+                        origin: cell_min
+                    });
+                },
+                GridCell::Material(data) => {
+                    let min = [0, 1, 2].map(|i| cell_min[i].max(self.min[i]));
+                    let max = [0, 1, 2].map(|i| cell_max[i].min(self.max[i]));
+                    self.region = Some(OctreeIterRegion {
+                        min,
+                        max,
+                        next: min,
+                        data
+                    });
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound = "T: VoxelPayload")]
+pub struct IndirectionGrid<T = [u8; 3]> {
     depth: u8,
-    cells: [GridCell; 8]
+    cells: [GridCell<T>; 8],
+    /// The most common material below this grid, shaded in its place when the renderer stops
+    /// descending early. Always [`GridCell::Empty`] or [`GridCell::Material`].
+    lod: GridCell<T>
 }
 
-impl Default for IndirectionGrid {
-    fn default() -> IndirectionGrid {
+impl<T> Default for IndirectionGrid<T> {
+    fn default() -> IndirectionGrid<T> {
         IndirectionGrid {
             depth: 0,
             cells: [
@@ -155,46 +828,102 @@ impl Default for IndirectionGrid {
                 GridCell::default(),
                 GridCell::default(),
                 GridCell::default()
-            ]
+            ],
+            lod: GridCell::default()
         }
     }
 }
 
 impl IndirectionGrid {
-    pub fn new(depth: u8) -> IndirectionGrid {
+    /// The size of a grid in the output of [`Octree::to_bytes`]: eight cells followed by the LOD cell.
+    pub const BYTE_LEN: usize = 9 * 4;
+}
+
+impl<T: VoxelPayload> IndirectionGrid<T> {
+    pub fn new(depth: u8) -> IndirectionGrid<T> {
         IndirectionGrid {
             depth,
             ..Default::default()
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.iter().all(|cell| *cell == GridCell::Empty)
+    }
+
+    /// The material shaded in place of this grid's subtree at a coarser level of detail.
+    pub fn lod(&self) -> Option<T> {
+        match self.lod {
+            GridCell::Material(data) => Some(data),
+            _ => None
+        }
+    }
+
+    /// Returns the cell this grid can be replaced with, if all eight cells are empty or hold the
+    /// same material.
+    pub fn uniform_cell(&self) -> Option<GridCell<T>> {
+        let first = self.cells[0];
+        match first {
+            GridCell::Empty | GridCell::Material(_) if self.cells.iter().all(|cell| *cell == first) => Some(first),
+            _ => None
+        }
+    }
 }
 
-#[derive(Copy, Clone, Debug, Serialize)]
-#[repr(C)]
-pub struct GridCell {
-    cell_type: GridCellType,
-    data: [u8; 3]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GridCell<T = [u8; 3]> {
+    #[default]
+    Empty,
+    GridPointer(u32),
+    Material(T),
+    /// An attachment index into [`Octree::attachments`] and the orientation it is placed with.
+    Attachment(u32, Orientation)
 }
 
-impl Default for GridCell {
-    fn default() -> GridCell {
-        GridCell {
-            cell_type: GridCellType::Empty,
-            data: [0u8; 3]
+impl<T: VoxelPayload> GridCell<T> {
+    pub fn cell_type(&self) -> GridCellType {
+        match self {
+            GridCell::Empty => GridCellType::Empty,
+            GridCell::GridPointer(_) => GridCellType::GridPointer,
+            GridCell::Material(_) => GridCellType::Material,
+            GridCell::Attachment(..) => GridCellType::Attachment
         }
     }
 }
 
-impl GridCell {
-    pub fn new(cell_type: GridCellType, data: [u8; 3]) -> GridCell {
-        GridCell {
-            cell_type,
-            data
+impl<T: PackedPayload> GridCell<T> {
+    /// Packs the cell for the GPU: the cell type in the low two bits and the pool index or
+    /// payload in the remaining thirty.
+    pub fn to_u32(&self) -> u32 {
+        let data = match self {
+            GridCell::Empty => 0,
+            GridCell::GridPointer(pool_index) => *pool_index,
+            GridCell::Material(data) => data.to_packed() & MAX_PACKED_PAYLOAD,
+            GridCell::Attachment(attachment, orientation) => (*attachment << 5) | u32::from(orientation.index())
+        };
+
+        (data << 2) | self.cell_type() as u32
+    }
+
+    pub fn from_u32(value: u32) -> Option<GridCell<T>> {
+        let data = value >> 2;
+        match GridCellType::try_from((value & 0x3) as u8).ok()? {
+            GridCellType::Empty => Some(GridCell::Empty),
+            GridCellType::GridPointer => Some(GridCell::GridPointer(data)),
+            GridCellType::Material => Some(GridCell::Material(T::from_packed(data)?)),
+            GridCellType::Attachment => Some(GridCell::Attachment(data >> 5, Orientation::from_index((data & 0x1F) as u8)?))
         }
     }
 }
 
-#[derive(Copy, Clone, Debug, Serialize)]
+/// The contents of a non-empty, non-pointer cell as returned by [`Octree::get`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VoxelData<T = [u8; 3]> {
+    Material(T),
+    Attachment(u32, Orientation)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum GridCellType {
     Empty,
@@ -202,3 +931,219 @@ pub enum GridCellType {
     Material,
     Attachment, // A child octree pointer + connection orientation (1 of 24 -- 6 faces * 4 orientations per face)
 }
+
+impl TryFrom<u8> for GridCellType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(GridCellType::Empty),
+            1 => Ok(GridCellType::GridPointer),
+            2 => Ok(GridCellType::Material),
+            3 => Ok(GridCellType::Attachment),
+            _ => Err(value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An octree with a collapsed region, scattered voxels and a grid released back to the pool.
+    fn sample() -> Octree {
+        let mut octree = Octree::new(5);
+        for x in 0..4 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    octree.add_data(x, y, z, [10, 20, 30]);
+                }
+            }
+        }
+        octree.add_data(31, 0, 17, [1, 2, 3]);
+        octree.add_data(9, 22, 5, [4, 5, 6]);
+        octree.add_data(30, 30, 30, [7, 8, 9]);
+        octree.remove_data(30, 30, 30);
+
+        octree
+    }
+
+    fn assert_same(octree: &Octree, other: &Octree) {
+        assert_eq!(other.depth_max, octree.depth_max);
+        assert_eq!(other.to_bytes(), octree.to_bytes());
+        assert_eq!(other.iter().collect::<Vec<_>>(), octree.iter().collect::<Vec<_>>());
+        assert_eq!(
+            other.indirection_pool.iter().map(|grid| grid.depth).collect::<Vec<_>>(),
+            octree.indirection_pool.iter().map(|grid| grid.depth).collect::<Vec<_>>()
+        );
+        assert_eq!(other.free_indices.iter().collect::<BTreeSet<_>>(), octree.free_indices.iter().collect::<BTreeSet<_>>());
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let octree = sample();
+        assert!(!octree.free_indices.is_empty());
+
+        let loaded = Octree::from_bytes(octree.depth_max, &octree.to_bytes()).unwrap();
+        assert_same(&octree, &loaded);
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let octree = sample();
+
+        let loaded: Octree = bincode::deserialize(&bincode::serialize(&octree).unwrap()).unwrap();
+        assert_same(&octree, &loaded);
+        assert_eq!(loaded.free_indices, octree.free_indices);
+    }
+
+    #[test]
+    fn collapsed_cells_survive() {
+        let loaded = Octree::from_bytes(5, &sample().to_bytes()).unwrap();
+
+        // The 4x4x4 block is a single material cell of a grid at depth 2.
+        assert_eq!(loaded.get_at_depth(3, 3, 3, 2), Some(VoxelData::Material([10, 20, 30])));
+        assert_eq!(loaded.get_at_depth(3, 3, 3, 1), None);
+        assert_eq!(loaded.iter().filter(|(.., data)| *data == [10, 20, 30]).count(), 64);
+    }
+
+    #[test]
+    fn free_list_recovered_from_unreachable_grids() {
+        let mut octree = Octree::new(4);
+        octree.add_data(15, 15, 15, [1, 1, 1]);
+        octree.remove_data(15, 15, 15);
+        let pool_len = octree.indirection_pool.len();
+        assert_eq!(octree.free_indices.len(), pool_len - 1);
+
+        let mut loaded = Octree::<[u8; 3]>::from_bytes(4, &octree.to_bytes()).unwrap();
+        assert_eq!(loaded.free_indices.iter().copied().collect::<BTreeSet<_>>(), (1..pool_len as u32).collect());
+
+        // Released indices are reused rather than growing the pool.
+        loaded.add_data(0, 0, 0, [2, 2, 2]);
+        assert_eq!(loaded.indirection_pool.len(), pool_len);
+    }
+
+    #[test]
+    fn grid_depths_recovered() {
+        let mut octree = Octree::new(6);
+        octree.add_data(63, 0, 63, [5, 5, 5]);
+
+        let loaded = Octree::<[u8; 3]>::from_bytes(6, &octree.to_bytes()).unwrap();
+        let mut depths: Vec<u8> = loaded.indirection_pool.iter().map(|grid| grid.depth).collect();
+        depths.sort_unstable();
+        assert_eq!(depths, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(loaded.get(63, 0, 63), Some(VoxelData::Material([5, 5, 5])));
+    }
+
+    #[test]
+    fn from_bytes_rejects_truncated_input() {
+        let bytes = sample().to_bytes();
+
+        assert!(Octree::<[u8; 3]>::from_bytes(5, &[]).is_none());
+        assert!(Octree::<[u8; 3]>::from_bytes(5, &bytes[..bytes.len() - 1]).is_none());
+        // Whole grids, but the pointers lead past the end of the pool.
+        assert!(Octree::<[u8; 3]>::from_bytes(5, &bytes[..IndirectionGrid::BYTE_LEN]).is_none());
+    }
+
+    #[test]
+    fn from_bytes_rejects_cycles() {
+        let grid = |cell: GridCell| {
+            let mut bytes = cell.to_u32().to_le_bytes().to_vec();
+            bytes.resize(IndirectionGrid::BYTE_LEN, 0);
+            bytes
+        };
+
+        let self_loop = [grid(GridCell::GridPointer(1)), grid(GridCell::GridPointer(1))].concat();
+        assert!(Octree::<[u8; 3]>::from_bytes(5, &self_loop).is_none());
+
+        let back_to_root = [grid(GridCell::GridPointer(1)), grid(GridCell::GridPointer(0))].concat();
+        assert!(Octree::<[u8; 3]>::from_bytes(5, &back_to_root).is_none());
+
+        let two_grid_loop = [grid(GridCell::GridPointer(1)), grid(GridCell::GridPointer(2)), grid(GridCell::GridPointer(1))].concat();
+        assert!(Octree::<[u8; 3]>::from_bytes(5, &two_grid_loop).is_none());
+    }
+
+    /// A compact encoding of a depth 5 octree, each grid given as its depth and the pool indices
+    /// its children point at.
+    fn compact(dag: bool, free_indices: &[u8], grids: &[(u8, [Option<u8>; 8])]) -> Vec<u8> {
+        let mut bytes = vec![5, dag as u8, grids.len() as u8, free_indices.len() as u8];
+        bytes.extend(free_indices);
+        for (depth, children) in grids {
+            bytes.push(*depth);
+            for child in children {
+                match child {
+                    Some(pool_index) => bytes.extend([GridCellType::GridPointer as u8, *pool_index]),
+                    None => bytes.push(GridCellType::Empty as u8)
+                }
+            }
+            bytes.push(GridCellType::Empty as u8);
+        }
+        bytes.push(0);
+        bytes
+    }
+
+    #[test]
+    fn compact_round_trip() {
+        for octree in [sample(), sample().to_dag()] {
+            let mut bytes = Vec::new();
+            octree.write_compact(&mut bytes);
+
+            let mut rest = &bytes[..];
+            let loaded = Octree::read_compact(&mut rest).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(loaded.dag, octree.dag);
+            assert_same(&octree, &loaded);
+        }
+    }
+
+    #[test]
+    fn read_compact_rejects_invalid_grids() {
+        let read = |bytes: Vec<u8>| Octree::<[u8; 3]>::read_compact(&mut &bytes[..]);
+        let one = |child: u8| [Some(child), None, None, None, None, None, None, None];
+        let none = [None; 8];
+
+        assert!(read(compact(false, &[2], &[(0, one(1)), (1, none), (0, none)])).is_some());
+        // A grid deeper than the octree, pointing at itself.
+        assert!(read(compact(false, &[], &[(0, one(1)), (255, one(1))])).is_none());
+        // A free grid listed twice, or still reachable from the root.
+        assert!(read(compact(false, &[2, 2], &[(0, one(1)), (1, none), (0, none)])).is_none());
+        assert!(read(compact(false, &[1], &[(0, one(1)), (1, none), (0, none)])).is_none());
+        // A grid pointed to by two cells is only allowed in a DAG.
+        let shared = [(0, [Some(1), Some(1), None, None, None, None, None, None]), (1, none)];
+        assert!(read(compact(false, &[], &shared)).is_none());
+        assert!(read(compact(true, &[], &shared)).is_some());
+    }
+
+    #[test]
+    fn changed_bytes_patch_stale_copy() {
+        let mut octree = sample();
+        let mut stale = octree.to_bytes();
+        octree.take_changes();
+
+        // Writes into existing grids, new grids past the end of the pool, collapses and releases.
+        octree.add_data(31, 0, 17, [9, 9, 9]);
+        octree.add_data(20, 20, 20, [1, 1, 1]);
+        octree.add_data(31, 31, 31, [2, 2, 2]);
+        octree.add_data(3, 3, 3, [3, 3, 3]);
+        octree.remove_data(9, 22, 5);
+        for x in 16..20 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    octree.add_data(x, y, z, [4, 4, 4]);
+                }
+            }
+        }
+
+        let changes = octree.take_changes();
+        assert!(!changes.relayout);
+        assert!(octree.to_bytes().len() > stale.len());
+        for (offset, bytes) in octree.changed_bytes(&changes) {
+            if stale.len() < offset + bytes.len() {
+                stale.resize(offset + bytes.len(), 0);
+            }
+            stale[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+
+        assert_eq!(stale, octree.to_bytes());
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+
+use crate::VoxelPayload;
+
+use super::{Octree, GridCell};
+
+/// A boolean operation between the voxels of two octrees.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum CsgOp {
+    Union,
+    Subtract,
+    Intersect,
+    Xor
+}
+
+/// Which operand a voxel of the result is taken from.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Pick {
+    A,
+    B,
+    Empty
+}
+
+impl CsgOp {
+    fn pick(&self, a_empty: bool, b_empty: bool) -> Pick {
+        match self {
+            CsgOp::Union => if b_empty { Pick::A } else { Pick::B },
+            CsgOp::Subtract => if b_empty { Pick::A } else { Pick::Empty },
+            CsgOp::Intersect => if b_empty { Pick::Empty } else { Pick::A },
+            CsgOp::Xor => match (a_empty, b_empty) {
+                (_, true) => Pick::A,
+                (true, false) => Pick::B,
+                (false, false) => Pick::Empty
+            }
+        }
+    }
+}
+
+impl<T: VoxelPayload> Octree<T> {
+    /// Writes every voxel of `other`, shifted by `offset`, over this octree.
+    ///
+    /// Both hierarchies are walked together: wherever `other` is uniform over one of this
+    /// octree's cells the result is decided for the whole cell, so untouched subtrees are kept or
+    /// replaced without visiting their voxels. Attachments in `other` are carried over only where
+    /// `offset` keeps their cells aligned with this octree's cells.
+    pub fn union(&mut self, other: &Octree<T>, offset: [i32; 3]) {
+        self.combine(other, offset, CsgOp::Union);
+    }
+
+    /// Clears every voxel that is occupied in `other`, shifted by `offset`.
+    pub fn subtract(&mut self, other: &Octree<T>, offset: [i32; 3]) {
+        self.combine(other, offset, CsgOp::Subtract);
+    }
+
+    /// Clears every voxel that is empty in `other`, shifted by `offset`.
+    pub fn intersect(&mut self, other: &Octree<T>, offset: [i32; 3]) {
+        self.combine(other, offset, CsgOp::Intersect);
+    }
+
+    /// Keeps the voxels occupied in exactly one of the two octrees, taking data from whichever
+    /// holds it.
+    pub fn xor(&mut self, other: &Octree<T>, offset: [i32; 3]) {
+        self.combine(other, offset, CsgOp::Xor);
+    }
+
+    fn combine(&mut self, other: &Octree<T>, offset: [i32; 3], op: CsgOp) {
+        if self.depth_max > 0 {
+            self.unshare();
+            let operand = Operand { other, offset, op };
+            let mut imported = HashMap::new();
+            self.combine_grid(0, [0, 0, 0], &operand, &other.root_cells(), &mut imported);
+        }
+    }
+
+    /// Combines the grid at `pool_index` with the cells of `other` overlapping it, `other_cells`.
+    fn combine_grid(
+        &mut self,
+        pool_index: u32,
+        grid_min: [u32; 3],
+        operand: &Operand<T>,
+        other_cells: &[OtherCell<T>],
+        imported: &mut HashMap<u32, u32>
+    ) {
+        let Operand { other, offset, op } = *operand;
+        let cell_size = self.cell_size(self.indirection_pool[pool_index as usize].depth);
+
+        for cell_index in 0..8u32 {
+            let cell_min = Octree::cell_min(grid_min, cell_index, cell_size);
+            let other_min = [0, 1, 2].map(|i| i64::from(cell_min[i]) - i64::from(offset[i]));
+            let other_cells = other.cells_in(other_cells, other_min, i64::from(cell_size));
+            let current = self.indirection_pool[pool_index as usize].cells[cell_index as usize];
+
+            let descend = match (current, other.uniform_cell_in(&other_cells, other_min, i64::from(cell_size))) {
+                (GridCell::GridPointer(_), Some(b)) => {
+                    let b_empty = b == GridCell::Empty;
+                    let pick = op.pick(false, b_empty);
+                    if pick != op.pick(true, b_empty) {
+                        true
+                    } else {
+                        if pick != Pick::A {
+                            let cell = self.picked_cell(pick, current, b, other, imported);
+                            self.replace_cell(pool_index, cell_index, cell);
+                        }
+                        false
+                    }
+                },
+                (_, Some(b)) => {
+                    let pick = op.pick(current == GridCell::Empty, b == GridCell::Empty);
+                    if pick != Pick::A {
+                        let cell = self.picked_cell(pick, current, b, other, imported);
+                        self.replace_cell(pool_index, cell_index, cell);
+                    }
+                    false
+                },
+                (_, None) => true
+            };
+
+            if !descend {
+                continue;
+            }
+
+            let child_pool_index = match current {
+                GridCell::GridPointer(child_pool_index) => child_pool_index,
+                GridCell::Empty => self.create_grid_child(pool_index, cell_index),
+                GridCell::Material(_) => self.split_grid_cell(pool_index, cell_index),
+                GridCell::Attachment(..) => continue
+            };
+
+            self.combine_grid(child_pool_index, cell_min, operand, &other_cells, imported);
+
+            if let Some(uniform_cell) = self.indirection_pool[child_pool_index as usize].uniform_cell() {
+                self.update_grid_cell(pool_index, cell_index, uniform_cell);
+                self.release_grid(child_pool_index);
+            }
+        }
+
+        self.update_lod(pool_index);
+    }
+
+    /// Resolves a [`Pick`] to a cell of this octree, importing `other`'s attachments on first use.
+    fn picked_cell(&mut self, pick: Pick, a: GridCell<T>, b: GridCell<T>, other: &Octree<T>, imported: &mut HashMap<u32, u32>) -> GridCell<T> {
+        match (pick, b) {
+            (Pick::A, _) => a,
+            (Pick::Empty, _) => GridCell::Empty,
+            (Pick::B, GridCell::Attachment(attachment, orientation)) => {
+                let attachment = *imported.entry(attachment)
+                    .or_insert_with(|| self.add_attachment(other.attachments[attachment as usize].clone()));
+                GridCell::Attachment(attachment, orientation)
+            },
+            (Pick::B, _) => b
+        }
+    }
+
+    fn replace_cell(&mut self, pool_index: u32, cell_index: u32, cell: GridCell<T>) {
+        if let GridCell::GridPointer(child_pool_index) = self.indirection_pool[pool_index as usize].cells[cell_index as usize] {
+            self.release_subtree(child_pool_index);
+        }
+        self.update_grid_cell(pool_index, cell_index, cell);
+    }
+
+    /// The cells of this octree making up its whole volume, as the start of a walk with
+    /// [`Octree::cells_in`].
+    fn root_cells(&self) -> Vec<OtherCell<T>> {
+        if self.depth_max == 0 {
+            return Vec::new();
+        }
+
+        vec![OtherCell {
+            cell: GridCell::GridPointer(0),
+            min: [0, 0, 0],
+            size: i64::from(self.size())
+        }]
+    }
+
+    /// Narrows `cells`, which cover the cube of side `size` at `min` among others, down to those
+    /// overlapping the cube. Pointers to grids larger than the cube are replaced by their cells, so
+    /// a walk down both octrees carries its place in this one along instead of descending from its
+    /// root for every cell.
+    fn cells_in(&self, cells: &[OtherCell<T>], min: [i64; 3], size: i64) -> Vec<OtherCell<T>> {
+        let mut overlapping = Vec::new();
+        let mut pending = cells.to_vec();
+        while let Some(other_cell) = pending.pop() {
+            if !other_cell.overlaps(min, size) {
+                continue;
+            }
+
+            match other_cell.cell {
+                GridCell::GridPointer(pool_index) if other_cell.size > size => {
+                    let child_size = other_cell.size / 2;
+                    let cells = &self.indirection_pool[pool_index as usize].cells;
+                    pending.extend(cells.iter().enumerate().map(|(cell_index, cell)| OtherCell {
+                        cell: *cell,
+                        min: [0, 1, 2].map(|i| other_cell.min[i] + ((cell_index as i64 >> i) & 1) * child_size),
+                        size: child_size
+                    }));
+                },
+                _ => overlapping.push(other_cell)
+            }
+        }
+
+        overlapping
+    }
+
+    /// Returns the single non-pointer cell covering the cube of side `size` at `min`, or `None` if
+    /// the cube holds a mix of cells, given the `cells` overlapping it from [`Octree::cells_in`].
+    /// Space outside the octree counts as empty, and so does space strictly inside an attachment,
+    /// which can't be split.
+    fn uniform_cell_in(&self, cells: &[OtherCell<T>], min: [i64; 3], size: i64) -> Option<GridCell<T>> {
+        let max = min.map(|v| v + size);
+        let bound = i64::from(self.size());
+
+        let mut uniform = if self.depth_max == 0 || (0..3).any(|i| min[i] < 0 || max[i] > bound) {
+            Some(GridCell::Empty)
+        } else {
+            None
+        };
+
+        for other_cell in cells {
+            let fits = match other_cell.cell {
+                GridCell::GridPointer(pool_index) => {
+                    self.uniform_cell_in_grid(pool_index, other_cell.min.map(|v| v as u32), min, max, &mut uniform)
+                },
+                cell => merge_uniform_cell(cell, other_cell.min, other_cell.size, min, max, &mut uniform)
+            };
+            if !fits {
+                return None;
+            }
+        }
+
+        uniform
+    }
+
+    fn uniform_cell_in_grid(&self, pool_index: u32, grid_min: [u32; 3], min: [i64; 3], max: [i64; 3], uniform: &mut Option<GridCell<T>>) -> bool {
+        let grid = &self.indirection_pool[pool_index as usize];
+        let grid_cell_size = self.cell_size(grid.depth);
+        let cell_size = i64::from(grid_cell_size);
+
+        for (cell_index, cell) in grid.cells.iter().enumerate() {
+            let cell_origin = Octree::cell_min(grid_min, cell_index as u32, grid_cell_size);
+            let cell_min = cell_origin.map(i64::from);
+
+            if (0..3).any(|i| cell_min[i] + cell_size <= min[i] || cell_min[i] >= max[i]) {
+                continue;
+            }
+
+            let fits = match cell {
+                GridCell::GridPointer(child_pool_index) => self.uniform_cell_in_grid(*child_pool_index, cell_origin, min, max, uniform),
+                cell => merge_uniform_cell(*cell, cell_min, cell_size, min, max, uniform)
+            };
+            if !fits {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// The octree combined into another, where it is placed and how.
+struct Operand<'a, T> {
+    other: &'a Octree<T>,
+    offset: [i32; 3],
+    op: CsgOp
+}
+
+/// A cell of the other octree during a CSG walk, with the cube it covers in that octree's space.
+#[derive(Copy, Clone)]
+struct OtherCell<T> {
+    cell: GridCell<T>,
+    min: [i64; 3],
+    size: i64
+}
+
+impl<T> OtherCell<T> {
+    fn overlaps(&self, min: [i64; 3], size: i64) -> bool {
+        (0..3).all(|i| self.min[i] < min[i] + size && min[i] < self.min[i] + self.size)
+    }
+}
+
+/// Merges a non-pointer cell covering the cube of side `cell_size` at `cell_min` into the
+/// `uniform` cell of the cube from `min` to `max`, returning `false` if they differ.
+fn merge_uniform_cell<T: VoxelPayload>(cell: GridCell<T>, cell_min: [i64; 3], cell_size: i64, min: [i64; 3], max: [i64; 3], uniform: &mut Option<GridCell<T>>) -> bool {
+    let cell = match cell {
+        GridCell::Attachment(..) if cell_min == min && cell_size == max[0] - min[0] => cell,
+        GridCell::Attachment(..) if (0..3).all(|i| cell_min[i] <= min[i] && cell_min[i] + cell_size >= max[i]) => GridCell::Empty,
+        GridCell::Attachment(..) => return false,
+        cell => cell
+    };
+
+    match uniform {
+        Some(uniform) => *uniform == cell,
+        None => {
+            *uniform = Some(cell);
+            true
+        }
+    }
+}
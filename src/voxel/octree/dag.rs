@@ -0,0 +1,116 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::VoxelPayload;
+
+use super::{Octree, GridCell, IndirectionGrid, ChangeTracker, MAX_POOL_INDEX};
+
+/// Maps grids to their index in a DAG pool while it is being built.
+struct DagBuilder<T> {
+    indirection_pool: Vec<IndirectionGrid<T>>,
+    /// Grids already added, keyed by depth and cells. Children are added first, so equal
+    /// keys mean equal subtrees.
+    nodes: HashMap<(u8, [GridCell<T>; 8]), u32>,
+    /// The DAG index of every grid of the source pool visited so far.
+    visited: HashMap<u32, u32>
+}
+
+impl<T: VoxelPayload> Octree<T> {
+    /// Returns a copy of this octree in which identical subtrees are stored once and shared by
+    /// every cell pointing to them, turning the tree into a sparse voxel DAG. The pool written by
+    /// [`Octree::to_bytes`] keeps the same layout, so the renderer walks it unchanged. Attached
+    /// octrees are deduplicated too, separately from the host.
+    ///
+    /// Editing a DAG first expands it back into a tree, so it is best kept for static data.
+    pub fn to_dag(&self) -> Octree<T> {
+        let mut builder = DagBuilder {
+            indirection_pool: vec![IndirectionGrid::default()],
+            nodes: HashMap::new(),
+            visited: HashMap::new()
+        };
+
+        let root = &self.indirection_pool[0];
+        let mut cells = root.cells;
+        for cell in &mut cells {
+            *cell = self.dag_cell(*cell, &mut builder);
+        }
+        builder.indirection_pool[0] = IndirectionGrid {
+            depth: 0,
+            cells,
+            lod: root.lod
+        };
+
+        Octree {
+            depth_max: self.depth_max,
+            free_indices: VecDeque::new(),
+            indirection_pool: builder.indirection_pool.into(),
+            attachments: self.attachments.iter().map(Self::to_dag).collect(),
+            changes: ChangeTracker::default(),
+            dag: true
+        }
+    }
+
+    /// Whether grids may be shared between several cells, see [`Octree::to_dag`].
+    pub fn is_dag(&self) -> bool {
+        self.dag
+    }
+
+    fn dag_cell(&self, cell: GridCell<T>, builder: &mut DagBuilder<T>) -> GridCell<T> {
+        let pool_index = match cell {
+            GridCell::GridPointer(pool_index) => pool_index,
+            _ => return cell
+        };
+
+        if let Some(dag_index) = builder.visited.get(&pool_index) {
+            return GridCell::GridPointer(*dag_index);
+        }
+
+        let grid = &self.indirection_pool[pool_index as usize];
+        let mut cells = grid.cells;
+        for cell in &mut cells {
+            *cell = self.dag_cell(*cell, builder);
+        }
+
+        let indirection_pool = &mut builder.indirection_pool;
+        let dag_index = *builder.nodes.entry((grid.depth, cells)).or_insert_with(|| {
+            indirection_pool.push(IndirectionGrid {
+                depth: grid.depth,
+                cells,
+                lod: grid.lod
+            });
+            (indirection_pool.len() - 1) as u32
+        });
+        builder.visited.insert(pool_index, dag_index);
+
+        GridCell::GridPointer(dag_index)
+    }
+
+    /// Expands a DAG back into a tree, giving every cell its own copy of the grids below it, so it
+    /// can be edited in place. Does nothing if the octree is already a tree.
+    pub(super) fn unshare(&mut self) {
+        if !self.dag {
+            return;
+        }
+
+        let mut indirection_pool = vec![self.indirection_pool[0].clone()];
+        let mut pending = vec![0usize];
+        while let Some(pool_index) = pending.pop() {
+            for cell_index in 0..8 {
+                if let GridCell::GridPointer(shared_pool_index) = indirection_pool[pool_index].cells[cell_index] {
+                    let child_pool_index = indirection_pool.len();
+                    if child_pool_index as u32 > MAX_POOL_INDEX {
+                        panic!("indirection pool exhausted!");
+                    }
+
+                    indirection_pool.push(self.indirection_pool[shared_pool_index as usize].clone());
+                    indirection_pool[pool_index].cells[cell_index] = GridCell::GridPointer(child_pool_index as u32);
+                    pending.push(child_pool_index);
+                }
+            }
+        }
+
+        self.indirection_pool = indirection_pool.into();
+        self.free_indices.clear();
+        self.dag = false;
+        self.changes.get_mut().relayout = true;
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{u24_to_bytes, VoxelPayload};
+
+use super::{Octree, GridCell, IndirectionGrid, MAX_POOL_INDEX};
+
+impl Octree {
+    /// Builds an octree from a dense buffer of `dims[0] * dims[1] * dims[2]` values laid out with x
+    /// varying fastest, then y, then z. Zero is empty; any other value is stored as material data,
+    /// as with [`u24_to_bytes`].
+    pub fn from_dense(dims: [u32; 3], data: &[u32]) -> Octree {
+        let len = dims.iter().map(|dim| *dim as usize).product::<usize>();
+        if data.len() != len {
+            panic!("dense buffer holds {} voxels, expected {}!", data.len(), len);
+        }
+
+        Octree::from_fn(dims, |x, y, z| {
+            let value = data[x as usize + dims[0] as usize * (y as usize + dims[1] as usize * z as usize)];
+            if value == 0 {
+                None
+            } else {
+                Some(u24_to_bytes(value))
+            }
+        })
+    }
+}
+
+impl<T: VoxelPayload> Octree<T> {
+    /// Builds the smallest octree covering `dims`, calling `f` once for every voxel inside `dims`.
+    /// The tree is built bottom-up: uniform children are merged before their parent is allocated,
+    /// so the pool only ever holds the grids of the final tree.
+    pub fn from_fn<F: FnMut(u32, u32, u32) -> Option<T>>(dims: [u32; 3], mut f: F) -> Octree<T> {
+        let depth_max = Octree::depth_for_size(dims[0].max(dims[1]).max(dims[2]));
+
+        let mut octree = Octree::new(depth_max);
+        octree.indirection_pool[0].cells = octree.build_grid(0, [0, 0, 0], dims, &mut f);
+        octree.update_lod(0);
+
+        octree
+    }
+
+    fn build_grid<F: FnMut(u32, u32, u32) -> Option<T>>(&mut self, depth: u8, grid_min: [u32; 3], dims: [u32; 3], f: &mut F) -> [GridCell<T>; 8] {
+        let cell_size = self.cell_size(depth);
+
+        let mut cells = [GridCell::Empty; 8];
+        for (cell_index, cell) in cells.iter_mut().enumerate() {
+            let cell_min = Octree::cell_min(grid_min, cell_index as u32, cell_size);
+            *cell = self.build_cell(depth, cell_min, dims, f);
+        }
+
+        cells
+    }
+
+    /// Builds the cell at `cell_min` of a grid at `depth`, allocating a child grid only if the
+    /// cell's contents aren't uniform.
+    fn build_cell<F: FnMut(u32, u32, u32) -> Option<T>>(&mut self, depth: u8, cell_min: [u32; 3], dims: [u32; 3], f: &mut F) -> GridCell<T> {
+        if (0..3).any(|i| cell_min[i] >= dims[i]) {
+            return GridCell::Empty;
+        }
+
+        if depth == self.depth_max - 1 {
+            return match f(cell_min[0], cell_min[1], cell_min[2]) {
+                Some(data) => GridCell::Material(data),
+                None => GridCell::Empty
+            };
+        }
+
+        let grid = IndirectionGrid {
+            depth: depth + 1,
+            cells: self.build_grid(depth + 1, cell_min, dims, f),
+            lod: GridCell::Empty
+        };
+
+        if let Some(cell) = grid.uniform_cell() {
+            return cell;
+        }
+
+        let pool_index = self.indirection_pool.len() as u32;
+        if pool_index > MAX_POOL_INDEX {
+            panic!("indirection pool exhausted!");
+        }
+
+        self.indirection_pool.push(grid);
+        self.update_lod(pool_index);
+
+        GridCell::GridPointer(pool_index)
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::VecDeque;
+
+use crate::{Orientation, VoxelPayload};
+
+use super::{Octree, GridCell, GridCellType, IndirectionGrid, ChangeTracker};
+
+impl<T: VoxelPayload> Octree<T> {
+    /// Appends a compact encoding of the octree, keeping its exact layout: the pool order, free
+    /// list, collapsed cells, LOD cells and attachments all survive [`Octree::read_compact`].
+    ///
+    /// The encoding is, with counts and indices as LEB128 varints:
+    /// `depth_max: u8`, `dag: u8`, grid count, free index count, the free indices, then per grid
+    /// its `depth: u8` and nine cells (eight children and the LOD), then the attachment count and
+    /// each attachment encoded the same way. A cell is its [`GridCellType`] as a `u8` followed by
+    /// the pool index of a pointer, the payload of a material serialized with bincode, or the
+    /// index and orientation (`u8`) of an attachment. Payloads are stored whole, unlike in
+    /// [`Octree::to_bytes`].
+    pub fn write_compact(&self, out: &mut Vec<u8>) {
+        out.push(self.depth_max);
+        out.push(self.dag as u8);
+        write_varint(out, self.indirection_pool.len() as u32);
+        write_varint(out, self.free_indices.len() as u32);
+        for pool_index in &self.free_indices {
+            write_varint(out, *pool_index);
+        }
+
+        for grid in self.indirection_pool.iter() {
+            out.push(grid.depth);
+            for cell in grid.cells.iter().chain([&grid.lod]) {
+                write_cell(out, cell);
+            }
+        }
+
+        write_varint(out, self.attachments.len() as u32);
+        for attachment in &self.attachments {
+            attachment.write_compact(out);
+        }
+    }
+
+    /// Reads an octree written by [`Octree::write_compact`] from the front of `bytes`, advancing
+    /// past it. Returns `None` if the encoding is truncated or describes an invalid octree: a grid
+    /// deeper than the octree, a pointer outside the pool or not one level down, a grid pointed to
+    /// twice outside a DAG, a free grid that is still reachable or listed twice, or an unknown
+    /// attachment.
+    pub fn read_compact(bytes: &mut &[u8]) -> Option<Octree<T>> {
+        Self::read_compact_at(bytes, 0)
+    }
+
+    fn read_compact_at(bytes: &mut &[u8], nesting: u8) -> Option<Octree<T>> {
+        // Attachments hold whole octrees; bound the nesting so hostile input can't blow the stack.
+        if nesting > 31 {
+            return None;
+        }
+
+        let depth_max = read_u8(bytes)?;
+        let dag = match read_u8(bytes)? {
+            0 => false,
+            1 => true,
+            _ => return None
+        };
+        let grid_count = read_varint(bytes)? as usize;
+        if depth_max > 31 || grid_count == 0 || grid_count > bytes.len() {
+            return None;
+        }
+
+        let free_count = read_varint(bytes)? as usize;
+        if free_count >= grid_count {
+            return None;
+        }
+        let mut free_indices = VecDeque::with_capacity(free_count);
+        for _ in 0..free_count {
+            let pool_index = read_varint(bytes)?;
+            if pool_index == 0 || pool_index as usize >= grid_count {
+                return None;
+            }
+            free_indices.push_back(pool_index);
+        }
+
+        let mut indirection_pool = Vec::with_capacity(grid_count);
+        for _ in 0..grid_count {
+            // The root of a depth 0 octree is its only grid, at depth 0.
+            let depth = read_u8(bytes)?;
+            if depth != 0 && depth >= depth_max {
+                return None;
+            }
+            let mut grid = IndirectionGrid::new(depth);
+            for cell in grid.cells.iter_mut().chain([&mut grid.lod]) {
+                *cell = read_cell(bytes)?;
+            }
+            if !matches!(grid.lod, GridCell::Empty | GridCell::Material(_)) {
+                return None;
+            }
+            indirection_pool.push(grid);
+        }
+
+        let attachment_count = read_varint(bytes)? as usize;
+        if attachment_count > bytes.len() {
+            return None;
+        }
+        let mut attachments = Vec::with_capacity(attachment_count);
+        for _ in 0..attachment_count {
+            attachments.push(Self::read_compact_at(bytes, nesting + 1)?);
+        }
+
+        // Pointers always lead one level down, which also rules out cycles.
+        if indirection_pool[0].depth != 0 {
+            return None;
+        }
+        for grid in &indirection_pool {
+            for cell in &grid.cells {
+                match *cell {
+                    GridCell::GridPointer(pool_index) => {
+                        let child = indirection_pool.get(pool_index as usize)?;
+                        let child_depth = grid.depth.checked_add(1)?;
+                        if pool_index == 0 || child_depth >= depth_max || child.depth != child_depth {
+                            return None;
+                        }
+                    },
+                    GridCell::Attachment(attachment, _) if attachment as usize >= attachments.len() => return None,
+                    _ => {}
+                }
+            }
+        }
+
+        // Grids reached from the root are live, and only reached once unless they may be shared.
+        // A free grid must be neither live nor listed twice, or it would be handed out twice.
+        let mut reached = vec![false; grid_count];
+        reached[0] = true;
+        let mut stack = vec![0];
+        while let Some(pool_index) = stack.pop() {
+            for cell in &indirection_pool[pool_index].cells {
+                if let GridCell::GridPointer(child) = *cell {
+                    if std::mem::replace(&mut reached[child as usize], true) {
+                        if !dag {
+                            return None;
+                        }
+                    } else {
+                        stack.push(child as usize);
+                    }
+                }
+            }
+        }
+        for pool_index in &free_indices {
+            if std::mem::replace(&mut reached[*pool_index as usize], true) {
+                return None;
+            }
+        }
+
+        Some(Octree {
+            depth_max,
+            free_indices,
+            indirection_pool: indirection_pool.into(),
+            attachments,
+            changes: ChangeTracker::default(),
+            dag
+        })
+    }
+}
+
+fn write_cell<T: VoxelPayload>(out: &mut Vec<u8>, cell: &GridCell<T>) {
+    out.push(cell.cell_type() as u8);
+    match cell {
+        GridCell::Empty => {},
+        GridCell::GridPointer(pool_index) => write_varint(out, *pool_index),
+        GridCell::Material(data) => bincode::serialize_into(&mut *out, data).unwrap(),
+        GridCell::Attachment(attachment, orientation) => {
+            write_varint(out, *attachment);
+            out.push(orientation.index());
+        }
+    }
+}
+
+fn read_cell<T: VoxelPayload>(bytes: &mut &[u8]) -> Option<GridCell<T>> {
+    match GridCellType::try_from(read_u8(bytes)?).ok()? {
+        GridCellType::Empty => Some(GridCell::Empty),
+        GridCellType::GridPointer => Some(GridCell::GridPointer(read_varint(bytes)?)),
+        GridCellType::Material => Some(GridCell::Material(bincode::deserialize_from(&mut *bytes).ok()?)),
+        GridCellType::Attachment => Some(GridCell::Attachment(read_varint(bytes)?, Orientation::from_index(read_u8(bytes)?)?))
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Option<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = read_u8(bytes)?;
+        if shift == 28 && byte & 0x70 != 0 {
+            return None;
+        }
+        value |= u32::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+fn read_u8(bytes: &mut &[u8]) -> Option<u8> {
+    let (first, rest) = bytes.split_first()?;
+    *bytes = rest;
+    Some(*first)
+}
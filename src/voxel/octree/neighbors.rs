@@ -0,0 +1,187 @@
+use crate::{Face, VoxelPayload};
+
+use super::{Octree, GridCell, VoxelData};
+
+/// Offsets of the 26 neighbors of a voxel, with x varying fastest, then y, then z.
+pub const NEIGHBOR_OFFSETS_26: [[i32; 3]; 26] = [
+    [-1, -1, -1], [0, -1, -1], [1, -1, -1],
+    [-1, 0, -1], [0, 0, -1], [1, 0, -1],
+    [-1, 1, -1], [0, 1, -1], [1, 1, -1],
+    [-1, -1, 0], [0, -1, 0], [1, -1, 0],
+    [-1, 0, 0], [1, 0, 0],
+    [-1, 1, 0], [0, 1, 0], [1, 1, 0],
+    [-1, -1, 1], [0, -1, 1], [1, -1, 1],
+    [-1, 0, 1], [0, 0, 1], [1, 0, 1],
+    [-1, 1, 1], [0, 1, 1], [1, 1, 1]
+];
+
+impl<T: VoxelPayload> Octree<T> {
+    /// Returns a cursor positioned on the given voxel, or `None` if it lies outside the octree.
+    pub fn cursor(&self, x: u32, y: u32, z: u32) -> Option<OctreeCursor<'_, T>> {
+        if !self.in_bounds(x, y, z) {
+            return None;
+        }
+
+        let mut cursor = OctreeCursor {
+            octree: self,
+            position: [x, y, z],
+            path: Vec::with_capacity(self.depth_max as usize),
+            cell: GridCell::Empty
+        };
+        cursor.path.push(0);
+        cursor.descend();
+
+        Some(cursor)
+    }
+
+    /// The neighbors across each face of the given voxel, in the order of [`Face::ALL`]. Voxels
+    /// outside the octree are `None`, as are empty ones.
+    pub fn neighbors6(&self, x: u32, y: u32, z: u32) -> [Option<VoxelData<T>>; 6] {
+        match self.cursor(x, y, z) {
+            Some(cursor) => Face::ALL.map(|face| cursor.neighbor(face)),
+            None => [None; 6]
+        }
+    }
+
+    /// The 26 voxels surrounding the given voxel, in the order of [`NEIGHBOR_OFFSETS_26`].
+    pub fn neighbors26(&self, x: u32, y: u32, z: u32) -> [Option<VoxelData<T>>; 26] {
+        match self.cursor(x, y, z) {
+            Some(cursor) => NEIGHBOR_OFFSETS_26.map(|offset| cursor.offset(offset)),
+            None => [None; 26]
+        }
+    }
+
+    /// Returns `true` if the given voxel holds material and the voxel across `face` doesn't, either
+    /// because it's empty, outside the octree, or covered by an attachment. Attached octrees rarely
+    /// fill their whole cell, so they don't hide the faces next to them.
+    pub fn is_face_exposed(&self, x: u32, y: u32, z: u32, face: Face) -> bool {
+        match self.cursor(x, y, z) {
+            Some(cursor) => {
+                matches!(cursor.get(), Some(VoxelData::Material(_)))
+                    && !matches!(cursor.neighbor(face), Some(VoxelData::Material(_)))
+            },
+            None => false
+        }
+    }
+
+    /// Descends from the grid at `pool_index`, which must contain `position`, to the non-pointer
+    /// cell containing it.
+    fn descend_from(&self, mut pool_index: u32, position: [u32; 3]) -> GridCell<T> {
+        loop {
+            let grid = &self.indirection_pool[pool_index as usize];
+            match grid.cells[Self::cell_index(position[0], position[1], position[2], self.cell_size(grid.depth)) as usize] {
+                GridCell::GridPointer(child_pool_index) => pool_index = child_pool_index,
+                cell => return cell
+            }
+        }
+    }
+}
+
+/// A position in an [`Octree`] that remembers the grids above it. Moving to a nearby voxel only
+/// climbs back to the smallest grid holding both voxels instead of restarting from the root.
+pub struct OctreeCursor<'a, T = [u8; 3]> {
+    octree: &'a Octree<T>,
+    position: [u32; 3],
+    /// Pool indices of the grids containing `position`, from the root down.
+    path: Vec<u32>,
+    /// The cell of the last grid in `path` containing `position`, which is never a pointer.
+    cell: GridCell<T>
+}
+
+impl<'a, T: VoxelPayload> OctreeCursor<'a, T> {
+    pub fn position(&self) -> [u32; 3] {
+        self.position
+    }
+
+    /// The data at the cursor, or `None` if the voxel is empty.
+    pub fn get(&self) -> Option<VoxelData<T>> {
+        voxel_data(self.cell)
+    }
+
+    /// Moves the cursor to the given voxel. Returns `false`, leaving the cursor in place, if the
+    /// voxel lies outside the octree.
+    pub fn move_to(&mut self, x: u32, y: u32, z: u32) -> bool {
+        if !self.octree.in_bounds(x, y, z) {
+            return false;
+        }
+
+        let shared_depth = self.shared_depth([x, y, z]);
+        self.path.truncate(shared_depth + 1);
+        self.position = [x, y, z];
+        self.descend();
+
+        true
+    }
+
+    /// Moves the cursor one voxel across `face`. Returns `false` if that leaves the octree.
+    pub fn step(&mut self, face: Face) -> bool {
+        match self.offset_position(face.normal()) {
+            Some([x, y, z]) => self.move_to(x, y, z),
+            None => false
+        }
+    }
+
+    /// The data in the voxel across `face`, without moving the cursor.
+    pub fn neighbor(&self, face: Face) -> Option<VoxelData<T>> {
+        self.offset(face.normal())
+    }
+
+    /// The data in the voxel at `offset` from the cursor, without moving it.
+    pub fn offset(&self, offset: [i32; 3]) -> Option<VoxelData<T>> {
+        let position = self.offset_position(offset)?;
+        if !self.octree.in_bounds(position[0], position[1], position[2]) {
+            return None;
+        }
+
+        let shared_depth = self.shared_depth(position);
+        voxel_data(self.octree.descend_from(self.path[shared_depth], position))
+    }
+
+    fn offset_position(&self, offset: [i32; 3]) -> Option<[u32; 3]> {
+        let mut position = [0; 3];
+        for i in 0..3 {
+            position[i] = u32::try_from(i64::from(self.position[i]) + i64::from(offset[i])).ok()?;
+        }
+
+        Some(position)
+    }
+
+    /// The depth of the deepest grid on the path that also contains `position`.
+    fn shared_depth(&self, position: [u32; 3]) -> usize {
+        let differing = (0..3).fold(0, |differing, i| differing | (position[i] ^ self.position[i]));
+        if differing == 0 {
+            return self.path.len() - 1;
+        }
+
+        // A grid at depth d spans the low `depth_max - d` bits of a coordinate.
+        let highest_bit = 31 - differing.leading_zeros() as usize;
+        (self.octree.depth_max as usize - highest_bit - 1).min(self.path.len() - 1)
+    }
+
+    /// Walks down from the last grid on the path to the cell containing the position.
+    fn descend(&mut self) {
+        let mut pool_index = *self.path.last().unwrap();
+        loop {
+            let grid = &self.octree.indirection_pool[pool_index as usize];
+            let cell = grid.cells[Octree::<T>::cell_index(self.position[0], self.position[1], self.position[2], self.octree.cell_size(grid.depth)) as usize];
+            match cell {
+                GridCell::GridPointer(child_pool_index) => {
+                    pool_index = child_pool_index;
+                    self.path.push(pool_index);
+                },
+                _ => {
+                    self.cell = cell;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn voxel_data<T: VoxelPayload>(cell: GridCell<T>) -> Option<VoxelData<T>> {
+    match cell {
+        GridCell::Material(data) => Some(VoxelData::Material(data)),
+        GridCell::Attachment(attachment, orientation) => Some(VoxelData::Attachment(attachment, orientation)),
+        _ => None
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::VecDeque;
+
+use serde::{Serialize, Deserialize};
+
+use crate::{Orientation, VoxelPayload};
+
+use super::{Octree, GridCell, IndirectionGrid};
+
+/// The changes turning one [`Octree`] into another, created by [`Octree::diff`] and applied with
+/// [`Octree::apply`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound = "T: VoxelPayload")]
+pub struct OctreePatch<T = [u8; 3]> {
+    depth_max: u8,
+    /// The number of attachments the diffed octree had. Attachments are matched by index, and
+    /// attachment tables only grow, so `attachments` holds those the target added past this.
+    attachment_base: u32,
+    attachments: Vec<Octree<T>>,
+    ops: Vec<PatchOp<T>>
+}
+
+/// A single edit of an [`OctreePatch`], addressing the cell at grid depth `depth` containing
+/// `origin`. Each op replaces the whole cell, including anything below it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PatchOp<T = [u8; 3]> {
+    Set { origin: [u32; 3], depth: u8, data: T },
+    Clear { origin: [u32; 3], depth: u8 },
+    /// Replaces the cell with a subtree, given as its cells in pre-order.
+    Replace { origin: [u32; 3], depth: u8, cells: Vec<PatchCell<T>> }
+}
+
+/// A cell of a subtree in a [`PatchOp::Replace`]. A `Grid` is followed by its eight cells.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PatchCell<T = [u8; 3]> {
+    Empty,
+    Material(T),
+    Attachment(u32, Orientation),
+    Grid
+}
+
+impl<T> Default for OctreePatch<T> {
+    fn default() -> OctreePatch<T> {
+        OctreePatch {
+            depth_max: 0,
+            attachment_base: 0,
+            attachments: Vec::new(),
+            ops: Vec::new()
+        }
+    }
+}
+
+impl<T: VoxelPayload> OctreePatch<T> {
+    /// Returns `true` if applying the patch changes nothing.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty() && self.attachments.is_empty()
+    }
+
+    pub fn ops(&self) -> &[PatchOp<T>] {
+        &self.ops
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<OctreePatch<T>> {
+        bincode::deserialize(bytes).ok()
+    }
+}
+
+impl<T: VoxelPayload> Octree<T> {
+    /// Returns the patch that turns this octree into `other`. Unchanged subtrees are skipped
+    /// entirely; changed cells become [`PatchOp::Set`] or [`PatchOp::Clear`] when `other` holds a
+    /// single value there, and a [`PatchOp::Replace`] carrying `other`'s subtree otherwise.
+    /// Octrees of different depths produce a patch that rebuilds the whole tree.
+    pub fn diff(&self, other: &Octree<T>) -> OctreePatch<T> {
+        let mut patch = OctreePatch {
+            depth_max: other.depth_max,
+            attachment_base: self.attachments.len() as u32,
+            attachments: other.attachments.iter().skip(self.attachments.len()).cloned().collect(),
+            ops: Vec::new()
+        };
+
+        if other.depth_max > 0 {
+            let pool_index = if self.depth_max == other.depth_max { Some(0) } else { None };
+            self.diff_grid(pool_index, other, 0, [0, 0, 0], &mut patch.ops);
+        }
+
+        patch
+    }
+
+    /// Compares the grid at `pool_index` of this octree, or an empty grid if `None`, against
+    /// `other`'s grid at `other_pool_index`.
+    fn diff_grid(&self, pool_index: Option<u32>, other: &Octree<T>, other_pool_index: u32, grid_min: [u32; 3], ops: &mut Vec<PatchOp<T>>) {
+        let other_grid = &other.indirection_pool[other_pool_index as usize];
+        let depth = other_grid.depth;
+        let cell_size = other.cell_size(depth);
+
+        for cell_index in 0..8u32 {
+            let origin = Octree::cell_min(grid_min, cell_index, cell_size);
+            let current = pool_index.map_or(GridCell::Empty, |pool_index| self.indirection_pool[pool_index as usize].cells[cell_index as usize]);
+            let target = other_grid.cells[cell_index as usize];
+
+            match (current, target) {
+                (GridCell::GridPointer(child_pool_index), GridCell::GridPointer(other_child_pool_index)) => {
+                    self.diff_grid(Some(child_pool_index), other, other_child_pool_index, origin, ops);
+                },
+                _ if current == target => {},
+                (_, GridCell::Empty) => ops.push(PatchOp::Clear { origin, depth }),
+                (_, GridCell::Material(data)) => ops.push(PatchOp::Set { origin, depth, data }),
+                (_, _) => {
+                    let mut cells = Vec::new();
+                    other.write_patch_cells(target, &mut cells);
+                    ops.push(PatchOp::Replace { origin, depth, cells });
+                }
+            }
+        }
+    }
+
+    fn write_patch_cells(&self, cell: GridCell<T>, cells: &mut Vec<PatchCell<T>>) {
+        match cell {
+            GridCell::Empty => cells.push(PatchCell::Empty),
+            GridCell::Material(data) => cells.push(PatchCell::Material(data)),
+            GridCell::Attachment(attachment, orientation) => cells.push(PatchCell::Attachment(attachment, orientation)),
+            GridCell::GridPointer(pool_index) => {
+                cells.push(PatchCell::Grid);
+                for cell in self.indirection_pool[pool_index as usize].cells {
+                    self.write_patch_cells(cell, cells);
+                }
+            }
+        }
+    }
+
+    /// Applies a patch created by [`Octree::diff`]. A patch for a different depth clears the
+    /// octree first. Returns `false`, leaving the octree untouched, if the patch is malformed.
+    pub fn apply(&mut self, patch: &OctreePatch<T>) -> bool {
+        if patch.depth_max > 31 || patch.attachment_base as usize > self.attachments.len() {
+            return false;
+        }
+
+        let attachment_count = (patch.attachment_base as usize + patch.attachments.len()).max(self.attachments.len());
+        let valid = patch.ops.iter().all(|op| {
+            let (origin, depth) = op.cell();
+            if depth >= patch.depth_max || (origin[0] | origin[1] | origin[2]) >> patch.depth_max != 0 {
+                return false;
+            }
+
+            match op {
+                PatchOp::Replace { cells, .. } => {
+                    patch_subtree_len(cells, 0, depth, patch.depth_max, attachment_count) == Some(cells.len())
+                },
+                _ => true
+            }
+        });
+        if !valid {
+            return false;
+        }
+
+        self.unshare();
+
+        let skip = self.attachments.len() - patch.attachment_base as usize;
+        for attachment in patch.attachments.iter().skip(skip) {
+            self.add_attachment(attachment.clone());
+        }
+
+        if self.depth_max != patch.depth_max {
+            self.depth_max = patch.depth_max;
+            self.indirection_pool = vec![IndirectionGrid::default()].into();
+            self.free_indices = VecDeque::new();
+            self.changes.get_mut().relayout = true;
+        }
+
+        for op in &patch.ops {
+            let ([x, y, z], depth) = op.cell();
+            match op {
+                PatchOp::Set { data, .. } => {
+                    self.set_cell(x, y, z, depth, GridCell::Material(*data));
+                },
+                PatchOp::Clear { .. } => {
+                    self.set_cell(x, y, z, depth, GridCell::Empty);
+                },
+                PatchOp::Replace { cells, .. } => {
+                    let cell = self.build_patch_cell(cells, &mut 0, depth);
+                    if !self.set_cell(x, y, z, depth, cell) {
+                        if let GridCell::GridPointer(pool_index) = cell {
+                            self.release_subtree(pool_index);
+                        }
+                    }
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Builds the subtree starting at `cells[*next]` for a cell of a grid at `depth`, merging
+    /// uniform grids as it goes.
+    fn build_patch_cell(&mut self, cells: &[PatchCell<T>], next: &mut usize, depth: u8) -> GridCell<T> {
+        let cell = cells[*next];
+        *next += 1;
+
+        match cell {
+            PatchCell::Empty => GridCell::Empty,
+            PatchCell::Material(data) => GridCell::Material(data),
+            PatchCell::Attachment(attachment, orientation) => GridCell::Attachment(attachment, orientation),
+            PatchCell::Grid => {
+                let mut grid = IndirectionGrid::new(depth + 1);
+                for cell_index in 0..8 {
+                    grid.cells[cell_index] = self.build_patch_cell(cells, next, depth + 1);
+                }
+
+                if let Some(cell) = grid.uniform_cell() {
+                    return cell;
+                }
+
+                let pool_index = self.alloc_grid(grid);
+                self.update_lod(pool_index);
+                GridCell::GridPointer(pool_index)
+            }
+        }
+    }
+}
+
+impl<T> PatchOp<T> {
+    /// The coordinates and grid depth of the cell the op replaces.
+    pub fn cell(&self) -> ([u32; 3], u8) {
+        match self {
+            PatchOp::Set { origin, depth, .. } | PatchOp::Clear { origin, depth } | PatchOp::Replace { origin, depth, .. } => (*origin, *depth)
+        }
+    }
+}
+
+/// The number of cells making up the subtree starting at `cells[start]`, or `None` if it runs past
+/// the end of `cells`, deeper than `depth_max`, or refers to a missing attachment.
+fn patch_subtree_len<T>(cells: &[PatchCell<T>], start: usize, depth: u8, depth_max: u8, attachment_count: usize) -> Option<usize> {
+    match cells.get(start)? {
+        PatchCell::Empty | PatchCell::Material(_) => Some(1),
+        PatchCell::Attachment(attachment, _) if (*attachment as usize) < attachment_count => Some(1),
+        PatchCell::Attachment(..) => None,
+        PatchCell::Grid => {
+            if depth + 1 >= depth_max {
+                return None;
+            }
+
+            let mut len = 1;
+            for _ in 0..8 {
+                len += patch_subtree_len(cells, start + len, depth + 1, depth_max, attachment_count)?;
+            }
+            Some(len)
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::{fmt, ops::{Index, IndexMut}, sync::Arc};
+use serde::{Serialize, Serializer, Deserialize, Deserializer, ser::SerializeSeq};
+
+use crate::VoxelPayload;
+
+use super::IndirectionGrid;
+
+/// The number of grids stored together in a chunk of a [`GridPool`].
+const CHUNK_LEN: usize = 256;
+
+/// The indirection pool of an [`super::Octree`], stored in fixed-size chunks behind `Arc`s. Cloning
+/// the pool only clones the `Arc`s; a chunk is copied the first time one of its grids is written
+/// while another clone still holds it, so clones share every chunk neither side has edited.
+///
+/// Serializes as a plain sequence of grids.
+#[derive(Clone)]
+pub(super) struct GridPool<T> {
+    chunks: Vec<Arc<Vec<IndirectionGrid<T>>>>,
+    len: usize
+}
+
+impl<T> GridPool<T> {
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = &IndirectionGrid<T>> {
+        self.chunks.iter().flat_map(|chunk| chunk.iter())
+    }
+}
+
+impl<T: Clone> GridPool<T> {
+    pub(super) fn new() -> GridPool<T> {
+        GridPool {
+            chunks: Vec::new(),
+            len: 0
+        }
+    }
+
+    pub(super) fn push(&mut self, grid: IndirectionGrid<T>) {
+        if self.len % CHUNK_LEN == 0 {
+            self.chunks.push(Arc::new(Vec::with_capacity(CHUNK_LEN)));
+        }
+
+        Arc::make_mut(self.chunks.last_mut().unwrap()).push(grid);
+        self.len += 1;
+    }
+}
+
+impl<T> Index<usize> for GridPool<T> {
+    type Output = IndirectionGrid<T>;
+
+    fn index(&self, index: usize) -> &IndirectionGrid<T> {
+        &self.chunks[index / CHUNK_LEN][index % CHUNK_LEN]
+    }
+}
+
+impl<T: Clone> IndexMut<usize> for GridPool<T> {
+    fn index_mut(&mut self, index: usize) -> &mut IndirectionGrid<T> {
+        &mut Arc::make_mut(&mut self.chunks[index / CHUNK_LEN])[index % CHUNK_LEN]
+    }
+}
+
+impl<T: Clone> From<Vec<IndirectionGrid<T>>> for GridPool<T> {
+    fn from(grids: Vec<IndirectionGrid<T>>) -> GridPool<T> {
+        grids.into_iter().collect()
+    }
+}
+
+impl<T: Clone> FromIterator<IndirectionGrid<T>> for GridPool<T> {
+    fn from_iter<I: IntoIterator<Item = IndirectionGrid<T>>>(grids: I) -> GridPool<T> {
+        let mut pool = GridPool::new();
+        for grid in grids {
+            pool.push(grid);
+        }
+
+        pool
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for GridPool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: VoxelPayload> Serialize for GridPool<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.len))?;
+        for grid in self.iter() {
+            seq.serialize_element(grid)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de, T: VoxelPayload> Deserialize<'de> for GridPool<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<GridPool<T>, D::Error> {
+        Vec::<IndirectionGrid<T>>::deserialize(deserializer).map(GridPool::from)
+    }
+}
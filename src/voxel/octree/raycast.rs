@@ -0,0 +1,176 @@
+use bevy::math::Vec3;
+
+use crate::{Face, Orientation, VoxelPayload};
+use super::{Octree, GridCell};
+
+/// The closest material hit found by [`Octree::raycast`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RayHit<T = [u8; 3]> {
+    /// The voxel containing the hit point.
+    pub voxel: [u32; 3],
+    pub data: T,
+    /// Distance along the normalized ray direction, in voxels.
+    pub distance: f32,
+    /// The face of the voxel the ray entered through.
+    pub normal: Face,
+    /// The attachment index, if the hit landed inside an attached octree.
+    pub attachment: Option<u32>
+}
+
+impl<T: VoxelPayload> Octree<T> {
+    /// Casts a ray through the octree in voxel space, where voxel (x, y, z) spans
+    /// `[x, x + 1] * [y, y + 1] * [z, z + 1]`. Mirrors `trace_voxel` in `voxel.wgsl`: cells are
+    /// visited in pool order and tested with the same slab intersection, keeping the nearest
+    /// material hit. Attachments are followed with the ray rotated into their frame.
+    pub fn raycast(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> Option<RayHit<T>> {
+        let dir = dir.normalize_or_zero();
+        if self.depth_max == 0 || dir == Vec3::ZERO {
+            return None;
+        }
+
+        let dir_inv = dir.recip();
+
+        let mut hit: Option<RayHit<T>> = None;
+        let mut hit_dist = max_dist;
+
+        let mut stack = Vec::with_capacity(self.depth_max as usize * 8);
+        stack.push((0u32, [0, 0, 0]));
+
+        while let Some((pool_index, grid_min)) = stack.pop() {
+            let grid = &self.indirection_pool[pool_index as usize];
+            let grid_cell_size = self.cell_size(grid.depth);
+            let cell_size = grid_cell_size as f32;
+
+            for cell_index in 0..8 {
+                let cell_origin = Octree::cell_min(grid_min, cell_index as u32, grid_cell_size);
+                let cell_min = Vec3::from(cell_origin.map(|v| v as f32));
+                let cell_max = cell_min + Vec3::splat(cell_size);
+
+                let (distance, normal) = match raybox_intersect(cell_min, cell_max, dir, dir_inv, origin) {
+                    Some(intersection) => intersection,
+                    None => continue
+                };
+
+                if distance > hit_dist {
+                    continue;
+                }
+
+                match grid.cells[cell_index] {
+                    GridCell::Empty => continue,
+                    GridCell::GridPointer(child_pool_index) => {
+                        stack.push((child_pool_index, cell_origin));
+                    },
+                    GridCell::Material(data) => {
+                        hit_dist = distance;
+                        hit = Some(RayHit {
+                            voxel: voxel_at(origin + dir * distance, normal, cell_min, cell_max),
+                            data,
+                            distance,
+                            normal,
+                            attachment: None
+                        });
+                    },
+                    GridCell::Attachment(attachment, orientation) => {
+                        let attached_hit = self.raycast_attachment(attachment, orientation, cell_min, cell_size, origin, dir, hit_dist);
+                        if let Some(attached_hit) = attached_hit {
+                            hit_dist = attached_hit.distance;
+                            hit = Some(attached_hit);
+                        }
+                    }
+                }
+            }
+        }
+
+        hit
+    }
+
+    /// Casts the ray through an attachment filling the cell at `cell_min`, returning the hit in
+    /// this octree's frame: `voxel` is the voxel of this octree holding the hit point, within the
+    /// attachment's cell.
+    #[allow(clippy::too_many_arguments)]
+    fn raycast_attachment(
+        &self,
+        attachment: u32,
+        orientation: Orientation,
+        cell_min: Vec3,
+        cell_size: f32,
+        origin: Vec3,
+        dir: Vec3,
+        max_dist: f32
+    ) -> Option<RayHit<T>> {
+        let octree = &self.attachments[attachment as usize];
+        let attachment_size = octree.size() as f32;
+        let scale = attachment_size / cell_size;
+        let inverse = orientation.inverse();
+
+        let local_origin = rotate(&inverse, origin - (cell_min + Vec3::splat(cell_size / 2.0))) * scale
+            + Vec3::splat(attachment_size / 2.0);
+        let local_dir = rotate(&inverse, dir);
+
+        let local_hit = octree.raycast(local_origin, local_dir, max_dist * scale)?;
+        let distance = local_hit.distance / scale;
+        let normal = orientation.rotate_face(local_hit.normal);
+
+        Some(RayHit {
+            voxel: voxel_at(origin + dir * distance, normal, cell_min, cell_min + Vec3::splat(cell_size)),
+            data: local_hit.data,
+            distance,
+            normal,
+            attachment: Some(attachment)
+        })
+    }
+}
+
+/// Returns the entry distance (clamped to 0 when starting inside the box) and entry face, matching
+/// `raybox_intersect` in `voxel.wgsl`.
+fn raybox_intersect(box_min: Vec3, box_max: Vec3, ray_dir: Vec3, ray_inv_dir: Vec3, ray_origin: Vec3) -> Option<(f32, Face)> {
+    let mut traverse_near = f32::NEG_INFINITY;
+    let mut traverse_far = f32::INFINITY;
+    let mut near_axis = 0;
+
+    for axis in 0..3 {
+        let tbot = ray_inv_dir[axis] * (box_min[axis] - ray_origin[axis]);
+        let ttop = ray_inv_dir[axis] * (box_max[axis] - ray_origin[axis]);
+        // 0 * inf is NaN when the ray lies in the slab's plane; f32::min/max skip NaNs.
+        let tmin = ttop.min(tbot);
+        let tmax = ttop.max(tbot);
+
+        if tmin > traverse_near {
+            traverse_near = tmin;
+            near_axis = axis;
+        }
+        traverse_far = traverse_far.min(tmax);
+    }
+
+    // Neither side is NaN, as `min` and `max` above skip NaNs.
+    if traverse_far <= traverse_near.max(0.0) {
+        return None;
+    }
+
+    let mut normal = [0, 0, 0];
+    normal[near_axis] = if ray_dir[near_axis] > 0.0 { -1 } else { 1 };
+
+    Some((traverse_near.max(0.0), Face::from_normal(normal).unwrap()))
+}
+
+/// The voxel behind the hit point on a face with the given normal, clamped to the cell it was
+/// found in.
+fn voxel_at(point: Vec3, normal: Face, cell_min: Vec3, cell_max: Vec3) -> [u32; 3] {
+    let mut voxel = point.floor();
+    let axis = normal.normal().iter().position(|n| *n != 0).unwrap();
+    if normal.normal()[axis] > 0 {
+        voxel[axis] = point[axis].ceil() - 1.0;
+    }
+
+    let voxel = voxel.clamp(cell_min, cell_max - Vec3::ONE);
+    [voxel.x as u32, voxel.y as u32, voxel.z as u32]
+}
+
+fn rotate(orientation: &Orientation, v: Vec3) -> Vec3 {
+    let matrix = orientation.matrix();
+    Vec3::new(
+        matrix[0][0] as f32 * v.x + matrix[0][1] as f32 * v.y + matrix[0][2] as f32 * v.z,
+        matrix[1][0] as f32 * v.x + matrix[1][1] as f32 * v.y + matrix[1][2] as f32 * v.z,
+        matrix[2][0] as f32 * v.x + matrix[2][1] as f32 * v.y + matrix[2][2] as f32 * v.z
+    )
+}
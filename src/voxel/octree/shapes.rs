@@ -0,0 +1,147 @@
+use bevy::math::{Vec3, Vec2};
+
+use crate::VoxelPayload;
+
+use super::{Octree, GridCell};
+
+/// How a cell relates to the shape being filled.
+enum Coverage {
+    Outside,
+    Inside,
+    Partial
+}
+
+impl<T: VoxelPayload> Octree<T> {
+    /// Fills the inclusive box `min..=max` with material data.
+    pub fn fill_box(&mut self, min: [u32; 3], max: [u32; 3], data: T) {
+        self.fill_region(&|cell_min, cell_size| box_coverage(min, max, cell_min, cell_size), GridCell::Material(data));
+    }
+
+    pub fn carve_box(&mut self, min: [u32; 3], max: [u32; 3]) {
+        self.fill_region(&|cell_min, cell_size| box_coverage(min, max, cell_min, cell_size), GridCell::Empty);
+    }
+
+    /// Fills every voxel whose center lies within `radius` of `center`, in voxel space.
+    pub fn fill_sphere(&mut self, center: Vec3, radius: f32, data: T) {
+        self.fill_sdf(|p| sphere_sdf(p, center, radius), data);
+    }
+
+    pub fn carve_sphere(&mut self, center: Vec3, radius: f32) {
+        self.carve_sdf(|p| sphere_sdf(p, center, radius));
+    }
+
+    /// Fills a Y-aligned cylinder standing on `base`, in voxel space.
+    pub fn fill_cylinder(&mut self, base: Vec3, radius: f32, height: f32, data: T) {
+        self.fill_sdf(|p| cylinder_sdf(p, base, radius, height), data);
+    }
+
+    pub fn carve_cylinder(&mut self, base: Vec3, radius: f32, height: f32) {
+        self.carve_sdf(|p| cylinder_sdf(p, base, radius, height));
+    }
+
+    /// Fills every voxel whose center has a signed distance `<= 0`. The distance must not grow
+    /// faster than the true Euclidean distance, since whole cells are accepted or rejected from a
+    /// single sample at their center.
+    pub fn fill_sdf<F: Fn(Vec3) -> f32>(&mut self, sdf: F, data: T) {
+        self.fill_region(&|cell_min, cell_size| sdf_coverage(&sdf, cell_min, cell_size), GridCell::Material(data));
+    }
+
+    pub fn carve_sdf<F: Fn(Vec3) -> f32>(&mut self, sdf: F) {
+        self.fill_region(&|cell_min, cell_size| sdf_coverage(&sdf, cell_min, cell_size), GridCell::Empty);
+    }
+
+    /// Writes `cell` over every voxel the shape covers, replacing cells that lie fully inside the
+    /// shape at the coarsest depth possible and only descending into partially covered ones.
+    fn fill_region(&mut self, coverage: &dyn Fn([u32; 3], u32) -> Coverage, cell: GridCell<T>) {
+        if self.depth_max > 0 {
+            self.unshare();
+            self.fill_grid(0, [0, 0, 0], coverage, cell);
+        }
+    }
+
+    fn fill_grid(&mut self, pool_index: u32, grid_min: [u32; 3], coverage: &dyn Fn([u32; 3], u32) -> Coverage, cell: GridCell<T>) {
+        let cell_size = self.cell_size(self.indirection_pool[pool_index as usize].depth);
+
+        for cell_index in 0..8u32 {
+            let cell_min = Octree::cell_min(grid_min, cell_index, cell_size);
+            let current = self.indirection_pool[pool_index as usize].cells[cell_index as usize];
+
+            match coverage(cell_min, cell_size) {
+                Coverage::Outside => {},
+                Coverage::Inside => {
+                    if current == cell {
+                        continue;
+                    }
+
+                    if let GridCell::GridPointer(child_pool_index) = current {
+                        self.release_subtree(child_pool_index);
+                    }
+                    self.update_grid_cell(pool_index, cell_index, cell);
+                },
+                Coverage::Partial => {
+                    let child_pool_index = match current {
+                        _ if current == cell => continue,
+                        GridCell::GridPointer(child_pool_index) => child_pool_index,
+                        GridCell::Empty => self.create_grid_child(pool_index, cell_index),
+                        GridCell::Material(_) => self.split_grid_cell(pool_index, cell_index),
+                        GridCell::Attachment(..) => continue
+                    };
+
+                    self.fill_grid(child_pool_index, cell_min, coverage, cell);
+
+                    if let Some(uniform_cell) = self.indirection_pool[child_pool_index as usize].uniform_cell() {
+                        self.update_grid_cell(pool_index, cell_index, uniform_cell);
+                        self.release_grid(child_pool_index);
+                    }
+                }
+            }
+        }
+
+        self.update_lod(pool_index);
+    }
+}
+
+fn box_coverage(min: [u32; 3], max: [u32; 3], cell_min: [u32; 3], cell_size: u32) -> Coverage {
+    let cell_max = cell_min.map(|v| v + cell_size - 1);
+
+    if (0..3).any(|i| cell_max[i] < min[i] || cell_min[i] > max[i]) {
+        Coverage::Outside
+    } else if (0..3).all(|i| cell_min[i] >= min[i] && cell_max[i] <= max[i]) {
+        Coverage::Inside
+    } else {
+        Coverage::Partial
+    }
+}
+
+fn sdf_coverage<F: Fn(Vec3) -> f32>(sdf: &F, cell_min: [u32; 3], cell_size: u32) -> Coverage {
+    let size = cell_size as f32;
+    let center = Vec3::new(cell_min[0] as f32, cell_min[1] as f32, cell_min[2] as f32) + Vec3::splat(size / 2.0);
+    let distance = sdf(center);
+
+    // The furthest voxel center in the cell from the cell's center.
+    let radius = (size - 1.0) * 3f32.sqrt() / 2.0;
+
+    if cell_size == 1 {
+        if distance <= 0.0 { Coverage::Inside } else { Coverage::Outside }
+    } else if distance > radius {
+        Coverage::Outside
+    } else if distance < -radius {
+        Coverage::Inside
+    } else {
+        Coverage::Partial
+    }
+}
+
+fn sphere_sdf(p: Vec3, center: Vec3, radius: f32) -> f32 {
+    (p - center).length() - radius
+}
+
+fn cylinder_sdf(p: Vec3, base: Vec3, radius: f32, height: f32) -> f32 {
+    let half_height = height / 2.0;
+    let d = Vec2::new(
+        Vec2::new(p.x - base.x, p.z - base.z).length() - radius,
+        (p.y - base.y - half_height).abs() - half_height
+    );
+
+    d.x.max(d.y).min(0.0) + d.max(Vec2::ZERO).length()
+}
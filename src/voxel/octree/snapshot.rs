@@ -0,0 +1,37 @@
+use std::ops::Deref;
+use serde::Serialize;
+
+use crate::VoxelPayload;
+
+use super::Octree;
+
+/// A read-only view of an [`Octree`] as it was when [`Octree::snapshot`] was called. Snapshots
+/// are `Send` and `Sync`, and can be read on another thread while the octree keeps being edited.
+#[derive(Clone, Debug, Serialize)]
+#[serde(transparent, bound = "T: VoxelPayload")]
+pub struct OctreeSnapshot<T = [u8; 3]>(Octree<T>);
+
+impl<T: VoxelPayload> Octree<T> {
+    /// Takes a snapshot of the octree. The snapshot shares the pool with the octree instead of
+    /// copying it: the octree copies a chunk of grids the first time it writes to it afterwards,
+    /// so taking a snapshot costs little more than the number of chunks, and an edit only copies
+    /// the chunks it touches.
+    pub fn snapshot(&self) -> OctreeSnapshot<T> {
+        OctreeSnapshot(self.clone())
+    }
+}
+
+impl<T: VoxelPayload> OctreeSnapshot<T> {
+    /// Returns an editable octree starting from the snapshot, sharing its grids the same way.
+    pub fn to_octree(&self) -> Octree<T> {
+        self.0.clone()
+    }
+}
+
+impl<T> Deref for OctreeSnapshot<T> {
+    type Target = Octree<T>;
+
+    fn deref(&self) -> &Octree<T> {
+        &self.0
+    }
+}
@@ -0,0 +1,76 @@
+use crate::VoxelPayload;
+
+use super::{Octree, GridCell, IndirectionGrid};
+
+/// Structure and memory figures for an [`Octree`] and the octrees attached to it, as returned by
+/// [`Octree::stats`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OctreeStats {
+    /// Grids in use, excluding those on the free list.
+    pub grids: usize,
+    /// Cells holding material or an attachment.
+    pub leaves: usize,
+    /// Released grids waiting on the free list to be reused.
+    pub free_grids: usize,
+    /// The number of grids in use at each grid depth. Attached octrees count their grids by their
+    /// own depth.
+    pub depth_histogram: Vec<usize>,
+    /// The length of the output of [`Octree::to_bytes`].
+    pub packed_bytes: usize,
+    /// Grids other than a root whose cells are all empty or all the same material, and could be
+    /// folded into their parent cell. Edits keep this at zero; octrees read with
+    /// [`Octree::from_bytes`] or patched by hand may not.
+    pub collapsible_grids: usize
+}
+
+impl OctreeStats {
+    /// The fraction of the grids in use that could be collapsed, from 0 to 1.
+    pub fn collapsible_fraction(&self) -> f32 {
+        if self.grids == 0 {
+            0.0
+        } else {
+            self.collapsible_grids as f32 / self.grids as f32
+        }
+    }
+}
+
+impl<T: VoxelPayload> Octree<T> {
+    /// Counts the grids and cells of the octree, walking the whole pool once.
+    pub fn stats(&self) -> OctreeStats {
+        let mut stats = OctreeStats {
+            packed_bytes: self.packed_grid_count() * IndirectionGrid::BYTE_LEN,
+            ..Default::default()
+        };
+        self.add_stats(&mut stats);
+
+        stats
+    }
+
+    fn add_stats(&self, stats: &mut OctreeStats) {
+        let mut free = vec![false; self.indirection_pool.len()];
+        for pool_index in &self.free_indices {
+            free[*pool_index as usize] = true;
+        }
+        stats.free_grids += self.free_indices.len();
+
+        for (pool_index, grid) in self.indirection_pool.iter().enumerate() {
+            if free[pool_index] {
+                continue;
+            }
+
+            stats.grids += 1;
+            if stats.depth_histogram.len() <= grid.depth as usize {
+                stats.depth_histogram.resize(grid.depth as usize + 1, 0);
+            }
+            stats.depth_histogram[grid.depth as usize] += 1;
+            stats.leaves += grid.cells.iter().filter(|cell| matches!(cell, GridCell::Material(_) | GridCell::Attachment(..))).count();
+            if pool_index != 0 && grid.uniform_cell().is_some() {
+                stats.collapsible_grids += 1;
+            }
+        }
+
+        for attachment in &self.attachments {
+            attachment.add_stats(stats);
+        }
+    }
+}
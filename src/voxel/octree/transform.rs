@@ -0,0 +1,56 @@
+use crate::{Axis, Orientation, VoxelPayload};
+
+use super::{Octree, GridCell, IndirectionGrid, ChangeTracker};
+
+impl<T: VoxelPayload> Octree<T> {
+    /// Returns a copy rotated by `orientation` about its center. Every grid has its cells permuted
+    /// in place, so the pool keeps its layout and no voxel is re-inserted. Attachments turn with
+    /// the octree.
+    pub fn transformed(&self, orientation: Orientation) -> Octree<T> {
+        self.permuted(&orientation.matrix(), |cell_orientation| orientation.then(&cell_orientation), self.attachments.clone())
+    }
+
+    /// Returns a copy mirrored along `axis` about its center. Attached octrees are mirrored too,
+    /// since a mirror image can't be produced by rotating them.
+    pub fn mirrored(&self, axis: Axis) -> Octree<T> {
+        let attachments = self.attachments.iter().map(|attachment| attachment.mirrored(axis)).collect();
+        self.permuted(&axis.mirror_matrix(), |cell_orientation| cell_orientation.mirrored(axis), attachments)
+    }
+
+    /// Applies the signed permutation `matrix` to the cells of every grid, and `orient` to the
+    /// orientation of every attachment cell.
+    fn permuted<F: Fn(Orientation) -> Orientation>(&self, matrix: &[[i32; 3]; 3], orient: F, attachments: Vec<Octree<T>>) -> Octree<T> {
+        // A cell's index holds one bit per axis, set on the positive side of the grid's center.
+        let mut permutation = [0usize; 8];
+        for (cell_index, permuted_index) in permutation.iter_mut().enumerate() {
+            for (axis, row) in matrix.iter().enumerate() {
+                let source_axis = row.iter().position(|value| *value != 0).unwrap();
+                let bit = (cell_index >> source_axis) & 1 == 1;
+                if bit != (row[source_axis] < 0) {
+                    *permuted_index |= 1 << axis;
+                }
+            }
+        }
+
+        let indirection_pool = self.indirection_pool.iter().map(|grid| {
+            let mut permuted = IndirectionGrid::new(grid.depth);
+            for (cell_index, cell) in grid.cells.iter().enumerate() {
+                permuted.cells[permutation[cell_index]] = match *cell {
+                    GridCell::Attachment(attachment, orientation) => GridCell::Attachment(attachment, orient(orientation)),
+                    _ => *cell
+                };
+            }
+            permuted.lod = grid.lod;
+            permuted
+        }).collect();
+
+        Octree {
+            depth_max: self.depth_max,
+            free_indices: self.free_indices.clone(),
+            indirection_pool,
+            attachments,
+            changes: ChangeTracker::default(),
+            dag: self.dag
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use serde::{Serialize, Deserialize};
+
+/// One of the six axis-aligned faces of a voxel or grid cell.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum Face {
+    PosX,
+    NegX,
+    PosY,
+    NegY,
+    PosZ,
+    NegZ
+}
+
+impl Face {
+    pub const ALL: [Face; 6] = [Face::PosX, Face::NegX, Face::PosY, Face::NegY, Face::PosZ, Face::NegZ];
+
+    pub fn normal(&self) -> [i32; 3] {
+        match self {
+            Face::PosX => [1, 0, 0],
+            Face::NegX => [-1, 0, 0],
+            Face::PosY => [0, 1, 0],
+            Face::NegY => [0, -1, 0],
+            Face::PosZ => [0, 0, 1],
+            Face::NegZ => [0, 0, -1]
+        }
+    }
+
+    pub fn from_normal(normal: [i32; 3]) -> Option<Face> {
+        Face::ALL.into_iter().find(|face| face.normal() == normal)
+    }
+
+    pub fn opposite(&self) -> Face {
+        match self {
+            Face::PosX => Face::NegX,
+            Face::NegX => Face::PosX,
+            Face::PosY => Face::NegY,
+            Face::NegY => Face::PosY,
+            Face::PosZ => Face::NegZ,
+            Face::NegZ => Face::PosZ
+        }
+    }
+}
+
+/// A coordinate axis, used to mirror along.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Axis {
+    X,
+    Y,
+    Z
+}
+
+impl Axis {
+    pub const ALL: [Axis; 3] = [Axis::X, Axis::Y, Axis::Z];
+
+    /// The row-major matrix negating this axis.
+    pub fn mirror_matrix(&self) -> [[i32; 3]; 3] {
+        let mut matrix = [[1, 0, 0], [0, 1, 0], [0, 0, 1]];
+        matrix[*self as usize][*self as usize] = -1;
+        matrix
+    }
+}
+
+/// One of the 24 axis-aligned rotations: the face the rotated +Y axis points towards, and the
+/// number of quarter turns about that face's normal.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Orientation {
+    pub face: Face,
+    pub rotation: u8
+}
+
+impl Default for Orientation {
+    fn default() -> Orientation {
+        Orientation::IDENTITY
+    }
+}
+
+impl Orientation {
+    pub const IDENTITY: Orientation = Orientation { face: Face::PosY, rotation: 0 };
+
+    pub fn new(face: Face, rotation: u8) -> Orientation {
+        Orientation {
+            face,
+            rotation: rotation % 4
+        }
+    }
+
+    /// The orientation's index in `0..24`, as packed into attachment cells.
+    pub fn index(&self) -> u8 {
+        self.face as u8 * 4 + self.rotation
+    }
+
+    pub fn from_index(index: u8) -> Option<Orientation> {
+        let face = *Face::ALL.get(usize::from(index / 4))?;
+        Some(Orientation::new(face, index % 4))
+    }
+
+    /// All 24 orientations, ordered by index.
+    pub fn all() -> impl Iterator<Item = Orientation> {
+        (0..24).filter_map(Orientation::from_index)
+    }
+
+    /// The rotation as a row-major signed permutation matrix.
+    pub fn matrix(&self) -> [[i32; 3]; 3] {
+        // Columns are the images of the x, y and z axes.
+        let base = match self.face {
+            Face::PosX => [[0, 1, 0], [-1, 0, 0], [0, 0, 1]],
+            Face::NegX => [[0, -1, 0], [1, 0, 0], [0, 0, 1]],
+            Face::PosY => [[1, 0, 0], [0, 1, 0], [0, 0, 1]],
+            Face::NegY => [[1, 0, 0], [0, -1, 0], [0, 0, -1]],
+            Face::PosZ => [[1, 0, 0], [0, 0, -1], [0, 1, 0]],
+            Face::NegZ => [[1, 0, 0], [0, 0, 1], [0, -1, 0]]
+        };
+
+        // A quarter turn about the unit axis n maps v to n x v + n (n . v).
+        let n = self.face.normal();
+        let quarter_turn = [
+            [n[0] * n[0], n[0] * n[1] - n[2], n[0] * n[2] + n[1]],
+            [n[1] * n[0] + n[2], n[1] * n[1], n[1] * n[2] - n[0]],
+            [n[2] * n[0] - n[1], n[2] * n[1] + n[0], n[2] * n[2]]
+        ];
+
+        (0..self.rotation).fold(base, |matrix, _| matrix_mul(&quarter_turn, &matrix))
+    }
+
+    pub fn from_matrix(matrix: &[[i32; 3]; 3]) -> Option<Orientation> {
+        Orientation::all().find(|orientation| orientation.matrix() == *matrix)
+    }
+
+    pub fn inverse(&self) -> Orientation {
+        let matrix = self.matrix();
+        let transpose = [0, 1, 2].map(|row| [0, 1, 2].map(|column| matrix[column][row]));
+        Orientation::from_matrix(&transpose).unwrap()
+    }
+
+    /// The orientation equivalent to applying `other` first, then `self`.
+    pub fn then(&self, other: &Orientation) -> Orientation {
+        Orientation::from_matrix(&matrix_mul(&self.matrix(), &other.matrix())).unwrap()
+    }
+
+    pub fn rotate(&self, v: [i32; 3]) -> [i32; 3] {
+        let matrix = self.matrix();
+        [0, 1, 2].map(|row| matrix[row][0] * v[0] + matrix[row][1] * v[1] + matrix[row][2] * v[2])
+    }
+
+    pub fn rotate_face(&self, face: Face) -> Face {
+        Face::from_normal(self.rotate(face.normal())).unwrap()
+    }
+
+    /// The rotation seen in a mirror along `axis`: mirroring, rotating by `self` and mirroring
+    /// back is a rotation again.
+    pub fn mirrored(&self, axis: Axis) -> Orientation {
+        let mirror = axis.mirror_matrix();
+        Orientation::from_matrix(&matrix_mul(&mirror, &matrix_mul(&self.matrix(), &mirror))).unwrap()
+    }
+}
+
+pub(crate) fn matrix_mul(a: &[[i32; 3]; 3], b: &[[i32; 3]; 3]) -> [[i32; 3]; 3] {
+    [0, 1, 2].map(|row| [0, 1, 2].map(|column| (0..3).map(|i| a[row][i] * b[i][column]).sum()))
+}
@@ -0,0 +1,86 @@
+use std::{fmt::Debug, hash::Hash};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{u24_to_bytes, bytes_to_u24};
+
+/// The largest value a payload can pack into a material cell, which keeps two bits for the cell type.
+pub const MAX_PACKED_PAYLOAD: u32 = (1 << 30) - 1;
+
+/// The data held by the material cells of an [`crate::Octree`]. Octrees only ever compare payloads
+/// for equality, merging neighbouring cells that hold the same one, so anything from material
+/// colors to light levels or ownership can be stored. Every type with these bounds is a payload.
+pub trait VoxelPayload: Copy + Eq + Hash + Debug + Send + Sync + Serialize + DeserializeOwned {}
+
+impl<T: Copy + Eq + Hash + Debug + Send + Sync + Serialize + DeserializeOwned> VoxelPayload for T {}
+
+/// A payload that can be packed into a GPU cell. Only [`crate::Octree::to_bytes`],
+/// [`crate::Octree::changed_bytes`] and [`crate::Octree::from_bytes`] need it; octrees that never
+/// reach the GPU can hold any [`VoxelPayload`].
+pub trait PackedPayload: VoxelPayload {
+    /// Packs the payload into the low thirty bits of a cell. Anything above
+    /// [`MAX_PACKED_PAYLOAD`] is truncated.
+    fn to_packed(&self) -> u32;
+
+    /// Unpacks a value written by [`PackedPayload::to_packed`], or returns `None` if it isn't one.
+    fn from_packed(value: u32) -> Option<Self>;
+}
+
+/// Material colors, packed as with [`bytes_to_u24`]. This is the payload the renderer expects.
+impl PackedPayload for [u8; 3] {
+    fn to_packed(&self) -> u32 {
+        bytes_to_u24(*self)
+    }
+
+    fn from_packed(value: u32) -> Option<Self> {
+        if value <= 0xFFFFFF {
+            Some(u24_to_bytes(value))
+        } else {
+            None
+        }
+    }
+}
+
+impl PackedPayload for bool {
+    fn to_packed(&self) -> u32 {
+        u32::from(*self)
+    }
+
+    fn from_packed(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None
+        }
+    }
+}
+
+impl PackedPayload for u8 {
+    fn to_packed(&self) -> u32 {
+        u32::from(*self)
+    }
+
+    fn from_packed(value: u32) -> Option<Self> {
+        u8::try_from(value).ok()
+    }
+}
+
+impl PackedPayload for u16 {
+    fn to_packed(&self) -> u32 {
+        u32::from(*self)
+    }
+
+    fn from_packed(value: u32) -> Option<Self> {
+        u16::try_from(value).ok()
+    }
+}
+
+/// Only the low thirty bits survive packing.
+impl PackedPayload for u32 {
+    fn to_packed(&self) -> u32 {
+        *self
+    }
+
+    fn from_packed(value: u32) -> Option<Self> {
+        Some(value)
+    }
+}